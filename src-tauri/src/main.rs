@@ -9,8 +9,8 @@ use sea_orm::FromJsonQueryResult;
 use serde_json::Value;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, env, fs, panic, backtrace::Backtrace, path::PathBuf, sync::{Arc, RwLock}};
-use tauri::{async_runtime, Emitter, Manager};
-use tauri_plugin_http::reqwest::{Client, header::{HeaderMap, HeaderName, HeaderValue}, Proxy};
+use tauri::{async_runtime, Emitter, Manager, RunEvent};
+use tauri_plugin_http::reqwest::{Client, header::{HeaderMap, HeaderName, HeaderValue}};
 use rand::{distributions::Alphanumeric, Rng};
 use walkdir::WalkDir;
 use services::{*, storage::*};
@@ -31,10 +31,35 @@ lazy_static! {
         df_ads: 30280,
         df_cdc: 7,
         auto_check_update: true,
+        max_retries: 3,
+        retry_backoff: 2,
+        downloader: "aria2".into(),
+        disk_check: "refuse".into(),
+        filename_template: "{ss_title}/{display_name}.{ext}".into(),
+        on_conflict: "skip".into(),
+        container: "mp4".into(),
+        audio_mode: "transcode".into(),
+        down_limit: 0,
+        task_down_limit: 0,
+        aria2: SettingsAria2 {
+            split: 20,
+            max_connection_per_server: 5,
+            min_split_size: 10,
+            disk_cache: 32,
+            file_allocation: "none".into()
+        },
+        schedule: SettingsSchedule {
+            mode: "off".into(),
+            start: "01:00".into(),
+            end: "07:00".into(),
+            limit: 0
+        },
         proxy: SettingsProxy {
             addr: String::new(),
             username: String::new(),
-            password: String::new()
+            password: String::new(),
+            no_proxy: String::new(),
+            rules: vec![]
         }
     }));
     static ref CURRENT_BIN: PathBuf = {
@@ -88,16 +113,10 @@ async fn init_client() -> Result<Client, String> {
     let config = CONFIG.read().unwrap();
     let client_builder = Client::builder()
         .default_headers(headers);
-    let client_builder = if !config.proxy.addr.is_empty() {
-        client_builder.proxy(
-            match config.proxy.addr.starts_with("https") {
-                true => Proxy::https(&config.proxy.addr),
-                false => Proxy::http(&config.proxy.addr),
-            }
-            .map_err(|e| handle_err(e))?
-            .basic_auth(&config.proxy.username, &config.proxy.password)
-        )
-    } else { client_builder };
+    let client_builder = match proxy::build(&config.proxy).map_err(handle_err)? {
+        Some(proxy) => client_builder.proxy(proxy),
+        None => client_builder
+    };
     Ok(client_builder.build().unwrap())
 }
 
@@ -110,16 +129,97 @@ struct Settings {
     df_ads: usize,
     df_cdc: usize,
     auto_check_update: bool,
+    max_retries: usize,
+    retry_backoff: u64,
+    downloader: String,
+    disk_check: String,
+    filename_template: String,
+    on_conflict: String,
+    container: String,
+    audio_mode: String,
+    down_limit: u64,
+    task_down_limit: u64,
+    schedule: SettingsSchedule,
+    aria2: SettingsAria2,
     proxy: SettingsProxy
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+struct SettingsSchedule {
+    mode: String,
+    start: String,
+    end: String,
+    limit: u64
+}
+
+// Sizes are in MiB
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+struct SettingsAria2 {
+    split: u32,
+    max_connection_per_server: u32,
+    min_split_size: u32,
+    disk_cache: u32,
+    file_allocation: String
+}
+
+impl SettingsAria2 {
+    // Same bounds aria2 enforces, so a bad value never reaches the sidecar
+    fn validate(&self) -> Result<(), String> {
+        if !(1..=16).contains(&self.max_connection_per_server) {
+            return Err(format!("max_connection_per_server must be between 1 and 16, got {}", self.max_connection_per_server));
+        }
+        if !(1..=1024).contains(&self.split) {
+            return Err(format!("split must be between 1 and 1024, got {}", self.split));
+        }
+        if !(1..=1024).contains(&self.min_split_size) {
+            return Err(format!("min_split_size must be between 1 and 1024 MiB, got {}", self.min_split_size));
+        }
+        if self.disk_cache > 4096 {
+            return Err(format!("disk_cache must be at most 4096 MiB, got {}", self.disk_cache));
+        }
+        if !["none", "prealloc", "trunc", "falloc"].contains(&self.file_allocation.as_str()) {
+            return Err(format!("Unknown file_allocation {}", self.file_allocation));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
 struct SettingsProxy {
     addr: String,
     username: String,
+    password: String,
+    // Comma separated hosts, domains or CIDRs that skip the proxy
+    #[serde(default)]
+    no_proxy: String,
+    #[serde(default)]
+    rules: Vec<SettingsProxyRule>
+}
+
+// `pattern` is a host ("*.bilivideo.com"), a host and path prefix
+// ("api.bilibili.com/pgc/") or an API family ("pgc", "pugv").
+// An empty `addr` sends matching requests direct.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+struct SettingsProxyRule {
+    pattern: String,
+    addr: String,
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
     password: String
 }
 
+impl SettingsProxyRule {
+    fn proxy(&self) -> SettingsProxy {
+        SettingsProxy {
+            addr: self.addr.clone(),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            ..Default::default()
+        }
+    }
+}
+
 #[tauri::command]
 async fn get_size(path: String, event: tauri::ipc::Channel<usize>) -> Result<usize, String> {
     let mut bytes = 0usize;
@@ -183,7 +283,37 @@ async fn rw_config(action: &str, settings: Option<HashMap<String, Value>>, secre
     if action == "init" || action == "read" {
         update_config(config::load().await.map_err(|e| handle_err(e))?);
     } else if action == "write" {
-        if let Some(new_config) = settings { update_config(new_config.clone()) }
+        if let Some(new_config) = settings {
+            let mut config_json = serde_json::to_value(&*CONFIG.read().unwrap()).unwrap();
+            if let Value::Object(ref mut config_obj) = config_json {
+                config_obj.extend(new_config.clone());
+            }
+            let candidate: Settings = serde_json::from_value(config_json).map_err(handle_err)?;
+            candidate.aria2.validate().map_err(handle_err)?;
+            bandwidth::validate(&candidate.schedule).map_err(handle_err)?;
+            template::validate(&candidate.filename_template).map_err(handle_err)?;
+            if !diskspace::MODES.contains(&candidate.disk_check.as_str()) {
+                return Err(handle_err(format!("Unknown disk check mode {}", candidate.disk_check)));
+            }
+            if !["skip", "overwrite", "rename", "keep_higher"].contains(&candidate.on_conflict.as_str()) {
+                return Err(handle_err(format!("Unknown on_conflict policy {}", candidate.on_conflict)));
+            }
+            ffmpeg::container(&candidate.container).map_err(handle_err)?;
+            if !ffmpeg::AUDIO_MODES.contains(&candidate.audio_mode.as_str()) {
+                return Err(handle_err(format!("Unknown audio mode {}", candidate.audio_mode)));
+            }
+            proxy::validate(&candidate.proxy).map_err(handle_err)?;
+            if candidate.downloader != "native" {
+                aria2c::validate_proxy(&candidate.proxy).map_err(handle_err)?;
+            }
+            let old_config = CONFIG.read().unwrap().clone();
+            update_config(new_config.clone());
+            let restart = aria2c::apply_config(&old_config, &candidate).await;
+            if !restart.is_empty() {
+                log::warn!("{:?} will take effect after a restart", restart);
+                window.emit("rw_config:restart", &restart).unwrap();
+            }
+        }
     }
     let config = CONFIG.read().unwrap().clone();
     if action != "read" {
@@ -276,8 +406,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .invoke_handler(tauri::generate_handler![
             ready, init, rw_config, get_size, clean_cache,
             login::exit, login::sms_login, login::pwd_login, login::switch_cookie, login::scan_login, login::refresh_cookie,
-            aria2c::handle_download, aria2c::push_back_queue, aria2c::process_queue])
-        .run(tauri::generate_context!())
-        .expect("error while running BiliTools");
+            aria2c::handle_download, aria2c::push_back_queue, aria2c::process_queue,
+            aria2c::pause_task, aria2c::resume_task, aria2c::cancel_task, aria2c::remove_task,
+            aria2c::move_task, aria2c::set_priority, aria2c::stop_queue, aria2c::get_scheduler_state,
+            history::search_history, history::delete_history, history::requeue_history,
+            history::export_history, history::import_history,
+            proxy::test_proxy])
+        .build(tauri::generate_context!())
+        .expect("error while building BiliTools")
+        .run(|_, event| if let RunEvent::Exit = event {
+            if let Err(e) = aria2c::kill() { log::error!("Failed to stop aria2c: {}", e); }
+            if let Err(e) = ffmpeg::kill() { log::error!("Failed to stop ffmpeg: {}", e); }
+        });
     Ok(())
 }
\ No newline at end of file
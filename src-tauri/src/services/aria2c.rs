@@ -1,26 +1,38 @@
 use lazy_static::lazy_static;
 use sea_orm::FromJsonQueryResult;
-use serde_json::{Value, json};
+use serde_json::{Map, Value, json};
 use serde::{Deserialize, Serialize};
-use std::{collections::VecDeque, fs, net::{SocketAddr, TcpListener}, path::PathBuf, process, sync::{Arc, RwLock}, time::Instant};
-use tokio::{sync::{Mutex, Notify}, time::{sleep, Duration}};
-use tauri::{async_runtime, Emitter, WebviewWindow};
+use std::{cmp::Reverse, collections::{HashMap, HashSet, VecDeque}, fs, net::{IpAddr, SocketAddr, TcpListener}, path::PathBuf, process, sync::{atomic::{AtomicBool, Ordering}, Arc, RwLock}, time::Instant};
+use tokio::{select, sync::{broadcast, watch, Mutex, Notify}, time::{interval, sleep, Duration}};
+use tauri::{async_runtime::{self, Receiver}, Emitter, WebviewWindow};
 use tauri_plugin_http::reqwest;
-use tauri_plugin_shell::{process::CommandChild, ShellExt};
+use rand::{distributions::Alphanumeric, Rng};
+use tauri_plugin_shell::{process::{CommandChild, CommandEvent}, ShellExt};
 
-use crate::{downloads, ffmpeg, get_app_handle, handle_err, init_client, CURRENT_BIN, SECRET, CONFIG};
+use crate::{aria2rpc::{self, Aria2EventKind, Aria2State, Aria2Status}, downloader::downloader, bandwidth, diskspace, conflict::{self, Resolution}, finalize, downloads, proxy, template, queue, ffmpeg, playurl, get_app_handle, get_window, handle_err, CURRENT_BIN, CONFIG, Settings, SettingsProxy};
 
 lazy_static! {
     pub static ref WAITING_QUEUE: Mutex<VecDeque<VideoInfo>> = Mutex::new(VecDeque::new());
     pub static ref DOING_QUEUE: Mutex<VecDeque<VideoInfo>> = Mutex::new(VecDeque::new());
     pub static ref COMPLETE_QUEUE: Mutex<VecDeque<VideoInfo>> = Mutex::new(VecDeque::new());
-    static ref ARIA2C_PORT: Arc<RwLock<usize>> = Arc::new(RwLock::new(0));
+    pub static ref FAILED_QUEUE: Mutex<VecDeque<VideoInfo>> = Mutex::new(VecDeque::new());
+    pub static ref ARIA2C_PORT: Arc<RwLock<usize>> = Arc::new(RwLock::new(0));
+    pub static ref ARIA2C_SECRET: Arc<RwLock<String>> = Arc::new(RwLock::new(String::new()));
     static ref ARIA2C_CHILD: Arc<RwLock<Option<CommandChild>>> = Arc::new(RwLock::new(None));
-    static ref DOWNLOAD_COMPLETED_NOTIFY: Notify = Notify::new();
+    static ref ARIA2C_WATCHER: Arc<RwLock<Option<CommandChild>>> = Arc::new(RwLock::new(None));
+    static ref ARIA2C_ALIVE: AtomicBool = AtomicBool::new(false);
+    static ref ARIA2C_EXITING: AtomicBool = AtomicBool::new(false);
+    static ref ARIA2C_GENERATION: watch::Sender<usize> = watch::channel(0).0;
+    static ref SCHEDULER_NOTIFY: Notify = Notify::new();
+    static ref SCHEDULER_RUNNING: AtomicBool = AtomicBool::new(false);
+    static ref CANCELLED: Arc<RwLock<HashSet<String>>> = Arc::new(RwLock::new(HashSet::new()));
+    static ref SAMPLED_TASKS: Arc<RwLock<HashMap<String, (DownloadTask, Value)>>> = Arc::new(RwLock::new(HashMap::new()));
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
 pub struct VideoInfo {
+    #[serde(default)]
+    pub id: String,
     pub gid: Value,
     pub display_name: String,
     pub video_path: PathBuf,
@@ -28,19 +40,73 @@ pub struct VideoInfo {
     pub output_path: PathBuf,
     pub tasks: Vec<DownloadTask>,
     pub action: String,
-    pub queue_info: QueueInfo
+    pub queue_info: QueueInfo,
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default)]
+    pub failure: Option<TaskFailure>,
+    #[serde(default)]
+    pub priority: i32
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    Expired,
+    Network,
+    DiskFull,
+    Removed,
+    Restarted,
+    #[default]
+    Other,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskFailure {
+    pub kind: FailureKind,
+    pub message: String,
+}
+
+impl From<String> for TaskFailure {
+    fn from(message: String) -> Self {
+        TaskFailure { kind: FailureKind::Other, message }
+    }
+}
+
+impl std::fmt::Display for TaskFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl FailureKind {
+    fn classify(code: Option<i64>, message: &str) -> Self {
+        match code {
+            Some(9) => FailureKind::DiskFull,
+            Some(3) | Some(24) => FailureKind::Expired,
+            Some(22) if message.contains("status=403") || message.contains("status=410") => FailureKind::Expired,
+            Some(2) | Some(6) | Some(19) => FailureKind::Network,
+            _ => FailureKind::Other,
+        }
+    }
+
+    fn retryable(&self) -> bool {
+        !matches!(self, FailureKind::DiskFull | FailureKind::Removed | FailureKind::Restarted)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
 pub struct DownloadTask {
     pub gid: String,
     pub display_name: String,
     pub url: Vec<String>,
     pub path: PathBuf,
     pub file_type: String,
+    #[serde(default)]
+    pub backend: String,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
 pub struct QueueInfo {
     pub title: String,
     pub display_name: String,
@@ -53,42 +119,227 @@ pub struct QueueInfo {
     pub ss_title: String,
     pub urls: QueueInfoURLs,
     pub time: String,
-    pub gids: Option<QueueInfoGIDs>
+    pub gids: Option<QueueInfoGIDs>,
+    #[serde(default)]
+    pub media_type: String,
+    #[serde(default)]
+    pub quality: Option<QueueInfoQuality>,
+    #[serde(default)]
+    pub upper: String,
+    #[serde(default)]
+    pub pubdate: String,
+    #[serde(default)]
+    pub container: String,
+    #[serde(default)]
+    pub audio_mode: String,
+    // 1-based position in its season or list, 0 when unknown. Unlike eid,
+    // which is a global episode id for bangumi, lessons and UGC seasons.
+    #[serde(default)]
+    pub index: i32
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
 pub struct QueueInfoURLs {
-    video: Vec<String>,
-    audio: Vec<String>,
+    pub video: Vec<String>,
+    pub audio: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+pub struct QueueInfoQuality {
+    pub dms: i32,
+    pub ads: i32,
+    pub cdc: i32,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
 pub struct QueueInfoGIDs {
     vgid: Option<String>,
     agid: Option<String>,
 }
 
-pub fn init() -> Result<(), String> {
-    let start_port = 6800;
+fn free_port(after: u16) -> Result<u16, String> {
+    // Skip the previous port, a crashed instance may still hold it in TIME_WAIT
+    let start_port = after.max(6799) + 1;
     let end_port = 65535;
-    let port = (start_port..end_port)
+    (start_port..end_port).chain(6800..start_port)
         .find_map(|port| TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).ok())
-        .ok_or("No free port found".to_string())?.local_addr().unwrap().port();
-    *ARIA2C_PORT.write().unwrap() = port as usize;
+        .map(|listener| listener.local_addr().unwrap().port())
+        .ok_or("No free port found".to_string())
+}
+
+// aria2 only speaks HTTP(S) to proxies, an empty address clears the proxy
+pub fn proxy_options(proxy: &SettingsProxy) -> Result<Vec<(&'static str, String)>, String> {
+    // "*" bypasses everything, which aria2 has no entry for
+    if proxy.addr.is_empty() || proxy.no_proxy.split(',').any(|entry| entry.trim() == "*") {
+        return Ok(vec![
+            ("all-proxy", String::new()), ("all-proxy-user", String::new()),
+            ("all-proxy-passwd", String::new()), ("no-proxy", String::new())
+        ]);
+    }
+    let mut url = proxy::proxy_url(proxy)?;
+    if !["http", "https"].contains(&url.scheme()) {
+        return Err(format!("aria2 doesn't support {} proxies, use an http:// proxy or the native downloader", url.scheme()));
+    }
+    // The credentials go in their own options, see PROXY_CREDENTIALS
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    Ok(vec![
+        ("all-proxy", url.as_str().trim_end_matches('/').to_string()),
+        ("all-proxy-user", proxy.username.clone()),
+        ("all-proxy-passwd", proxy.password.clone()),
+        ("no-proxy", aria2_no_proxy(&proxy.no_proxy)),
+    ])
+}
+
+// Rules are routed per task, so each of them has to suit aria2 as well
+pub fn validate_proxy(proxy: &SettingsProxy) -> Result<(), String> {
+    proxy_options(proxy)?;
+    for rule in &proxy.rules {
+        proxy_options(&rule.proxy()).map_err(|e| format!("Proxy rule {}: {}", rule.pattern, e))?;
+    }
+    Ok(())
+}
+
+// Kept off the command line, which any local user can read, and handed
+// over through RPC once aria2c is up
+const PROXY_CREDENTIALS: [&str; 2] = ["all-proxy-user", "all-proxy-passwd"];
+
+async fn send_proxy_credentials() -> Result<(), String> {
+    downloader("aria2").ready().await?;
+    let options = proxy_options(&CONFIG.read().unwrap().proxy)?.into_iter()
+        .filter(|(key, _)| PROXY_CREDENTIALS.contains(key))
+        .map(|(key, value)| (key.to_string(), Value::from(value)))
+        .collect();
+    aria2rpc::change_global_option(options).await.map(|_| ()).map_err(|e| e.to_string())
+}
+
+// aria2 matches subdomains only for entries starting with a dot, and the
+// domain itself only for bare entries. proxy::bypassed takes every domain
+// entry as both, so spell out both forms.
+fn aria2_no_proxy(no_proxy: &str) -> String {
+    no_proxy.split(',').map(str::trim).filter(|entry| !entry.is_empty())
+        .flat_map(|entry| {
+            if entry.contains('/') || entry.parse::<IpAddr>().is_ok() {
+                return vec![entry.to_string()];
+            }
+            let domain = entry.trim_start_matches("*.").trim_start_matches('.');
+            vec![domain.to_string(), format!(".{}", domain)]
+        })
+        .collect::<Vec<_>>().join(",")
+}
+
+// Only needed when rules route some hosts differently from the global
+// proxy, an empty all-proxy on the task makes aria2 go direct.
+pub fn task_proxy_options(urls: &[String]) -> Result<Map<String, Value>, String> {
+    let config = CONFIG.read().unwrap().proxy.clone();
+    let Some(url) = urls.first().and_then(|url| reqwest::Url::parse(url).ok()) else { return Ok(Map::new()) };
+    if config.rules.is_empty() { return Ok(Map::new()); }
+    let routed = proxy::route(&config, &url).unwrap_or_default();
+    Ok(proxy_options(&routed)?.into_iter()
+        .filter(|(key, _)| *key != "no-proxy")
+        .map(|(key, value)| (key.to_string(), Value::from(value)))
+        .collect())
+}
+
+fn tuning_options() -> Vec<(&'static str, String)> {
+    let aria2 = CONFIG.read().unwrap().aria2.clone();
+    vec![
+        ("split", aria2.split.to_string()),
+        ("max-connection-per-server", aria2.max_connection_per_server.to_string()),
+        ("min-split-size", format!("{}M", aria2.min_split_size)),
+        ("disk-cache", format!("{}M", aria2.disk_cache)),
+        ("file-allocation", aria2.file_allocation),
+    ]
+}
+
+// Pushes whatever changed in the settings to the running instance and
+// returns the fields that only take effect once aria2c is restarted. Tuning
+// options become the defaults for downloads added afterwards, but aria2
+// can't resize its disk cache at runtime.
+pub async fn apply_config(old: &Settings, new: &Settings) -> Vec<&'static str> {
+    let mut restart = vec![];
+    let mut options = Map::new();
+    if old.max_conc != new.max_conc {
+        options.insert("max-concurrent-downloads".into(), new.max_conc.to_string().into());
+    }
+    if old.aria2 != new.aria2 {
+        options.extend(tuning_options().into_iter()
+            .filter(|(key, _)| *key != "disk-cache")
+            .map(|(key, value)| (key.to_string(), Value::from(value))));
+        if old.aria2.disk_cache != new.aria2.disk_cache {
+            restart.push("aria2.disk_cache");
+        }
+    }
+    if old.proxy != new.proxy {
+        match proxy_options(&new.proxy) {
+            Ok(proxy) => {
+                let proxy: Map<String, Value> = proxy.into_iter()
+                    .map(|(key, value)| (key.to_string(), Value::from(value))).collect();
+                // The global option only covers downloads added later,
+                // and rules may route each queued one differently
+                for task in queued_aria2_tasks().await {
+                    let options = match task_proxy_options(&task.url) {
+                        Ok(options) if !options.is_empty() => options,
+                        Ok(_) => proxy.clone(),
+                        Err(e) => { log::warn!("Failed to route {}: {}", task.gid, e); continue; }
+                    };
+                    if let Err(e) = aria2rpc::change_option(&task.gid, options).await {
+                        log::warn!("Failed to apply proxy to {}: {}", task.gid, e);
+                    }
+                }
+                options.extend(proxy);
+            },
+            Err(e) => { handle_err(e); }
+        }
+    }
+    if !options.is_empty() {
+        log::info!("Applying {:?} to aria2c", options.keys().collect::<Vec<_>>());
+        if let Err(e) = aria2rpc::change_global_option(options).await {
+            handle_err(e);
+        }
+    }
+    if old.down_limit != new.down_limit || old.task_down_limit != new.task_down_limit || old.schedule != new.schedule {
+        if let Err(e) = bandwidth::apply().await {
+            handle_err(e);
+        }
+    }
+    reschedule();
+    restart
+}
+
+fn spawn_sidecar() -> Result<Receiver<CommandEvent>, String> {
+    let port = free_port(*ARIA2C_PORT.read().unwrap() as u16)?;
+    let secret: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(16).map(char::from)
+        .collect();
     let app = get_app_handle();
-    let (_, child) = app.shell().sidecar("./bin/aria2c").unwrap()
+    let (rx, child) = app.shell().sidecar("./bin/aria2c").unwrap()
         .current_dir(&*CURRENT_BIN)
         .args([
             format!("--conf-path={}", CURRENT_BIN.join("aria2.conf").to_string_lossy()),
             format!("--rpc-listen-port={}", port),
-            format!("--rpc-secret={}", &SECRET.read().unwrap()),
-            format!("--max-concurrent-downloads={}", CONFIG.read().unwrap().max_conc)
-        ]).spawn().map_err(|e| handle_err(e))?;
+            format!("--rpc-secret={}", secret),
+            format!("--max-concurrent-downloads={}", CONFIG.read().unwrap().max_conc),
+            format!("--stop-with-process={}", process::id())
+        ])
+        .args(tuning_options().into_iter().map(|(key, value)| format!("--{}={}", key, value)))
+        .args(match proxy_options(&CONFIG.read().unwrap().proxy) {
+            Ok(options) => options.into_iter()
+                .filter(|(key, value)| !value.is_empty() && !PROXY_CREDENTIALS.contains(key))
+                .map(|(key, value)| format!("--{}={}", key, value)).collect(),
+            Err(e) => { handle_err(format!("Not using the proxy for downloads: {}", e)); vec![] }
+        })
+        .spawn().map_err(handle_err)?;
+    *ARIA2C_PORT.write().unwrap() = port as usize;
+    *ARIA2C_SECRET.write().unwrap() = secret;
 
+    // --stop-with-process polls once a second, these watchers are a second
+    // line of defence in case aria2 is too busy to notice.
     let pid = child.pid();
     *ARIA2C_CHILD.write().unwrap() = Some(child);
     #[cfg(target_os = "windows")]
-    app.shell().sidecar("C:\\Windows\\System32\\WindowsPowerShell\\v1.0\\powershell.exe").unwrap()
+    let (_, watcher) = app.shell().sidecar("C:\\Windows\\System32\\WindowsPowerShell\\v1.0\\powershell.exe").unwrap()
         .args([
             "-Command",
             &format!(
@@ -99,34 +350,242 @@ pub fn init() -> Result<(), String> {
             )
         ]).spawn().map_err(|e| handle_err(e))?;
 
-    #[cfg(target_os = "macos")]
-    app.shell().sidecar("/bin/bash").unwrap()
+    #[cfg(unix)]
+    let (_, watcher) = app.shell().command("/bin/sh")
         .args([
             "-c",
             &format!(
-                "while kill -0 {} 2>/dev/null; do sleep 0.5; done; kill {}",
+                "while kill -0 {} 2>/dev/null; do sleep 0.5; done; kill {} 2>/dev/null",
                 process::id(),
                 pid
             )
         ]).spawn().map_err(|e| e.to_string())?;
+    *ARIA2C_WATCHER.write().unwrap() = Some(watcher);
+
+    log::info!("aria2c started on port {} (pid {})", port, pid);
+    Ok(rx)
+}
 
+pub fn init() -> Result<(), String> {
+    async_runtime::spawn(progress_sampler());
+    async_runtime::spawn(scheduler());
+    async_runtime::spawn(bandwidth::watch());
+    let rx = spawn_sidecar()?;
+    ARIA2C_ALIVE.store(true, Ordering::SeqCst);
+    async_runtime::spawn(aria2rpc::listen());
+    async_runtime::spawn(supervise(rx));
     Ok(())
 }
 
+async fn supervise(mut rx: Receiver<CommandEvent>) {
+    let mut failures = 0;
+    loop {
+        let started = Instant::now();
+        let mut code = None;
+        while let Some(event) = rx.recv().await {
+            match event {
+                CommandEvent::Stderr(line) => log::warn!("aria2c: {}", String::from_utf8_lossy(&line).trim_end()),
+                CommandEvent::Error(e) => log::error!("aria2c: {}", e),
+                CommandEvent::Terminated(payload) => { code = payload.code; break; },
+                _ => ()
+            }
+        }
+        ARIA2C_ALIVE.store(false, Ordering::SeqCst);
+        ARIA2C_CHILD.write().unwrap().take();
+        // Its pid may be handed to another process, which the watcher would kill on exit
+        if let Some(watcher) = ARIA2C_WATCHER.write().unwrap().take() {
+            let _ = watcher.kill();
+        }
+        if ARIA2C_EXITING.load(Ordering::SeqCst) { return; }
+        // Only back off when it keeps dying right after being started
+        failures = if started.elapsed() > Duration::from_secs(60) { 1 } else { failures + 1 };
+        let delay = 1u64 << (failures - 1).min(5);
+        log::error!("aria2c exited unexpectedly (code {:?}), restarting in {}s", code, delay);
+        get_window().emit("aria2c", json!({ "state": "restarting", "code": code })).unwrap();
+        sleep(Duration::from_secs(delay)).await;
+        if ARIA2C_EXITING.load(Ordering::SeqCst) { return; }
+        match spawn_sidecar() {
+            Ok(new_rx) => {
+                rx = new_rx;
+                if let Err(e) = recover().await {
+                    // An instance that never answers is no use, start over with the next one
+                    log::error!("Failed to recover tasks after restarting aria2c: {}", e);
+                    if let Some(child) = ARIA2C_CHILD.write().unwrap().take() {
+                        let _ = child.kill();
+                    }
+                }
+            },
+            Err(e) => {
+                log::error!("Failed to restart aria2c: {}", e);
+                // A closed channel sends us straight into the next attempt
+                rx = async_runtime::channel(1).1;
+            }
+        }
+    }
+}
+
+// Re-registers every queued aria2 task with the new instance, so waiters
+// stuck on the dead one can pick up their new gids.
+async fn recover() -> Result<(), String> {
+    downloader("aria2").ready().await?;
+    // Before any task is re-added, or they would go out without them
+    if let Err(e) = send_proxy_credentials().await {
+        log::warn!("Failed to pass the proxy credentials to aria2c: {}", e);
+    }
+    let infos = {
+        let waiting_queue = WAITING_QUEUE.lock().await;
+        let doing_queue = DOING_QUEUE.lock().await;
+        waiting_queue.iter().chain(doing_queue.iter()).cloned().collect::<Vec<_>>()
+    };
+    let mut recovered = 0;
+    for info in infos {
+        if info.tasks.iter().all(|t| t.backend == "native") { continue; }
+        let info = match reconcile(info.clone()).await {
+            Ok(info) => info,
+            Err(e) => { log::warn!("Failed to re-add {}: {}", info.display_name, e); continue; }
+        };
+        let mut waiting_queue = WAITING_QUEUE.lock().await;
+        let mut doing_queue = DOING_QUEUE.lock().await;
+        if let Some(queued) = find_task(&mut doing_queue, &info.id).or(find_task(&mut waiting_queue, &info.id)) {
+            queued.tasks = info.tasks;
+            queued.gid = info.gid;
+            recovered += 1;
+        }
+    }
+    log::info!("Re-added {} tasks to aria2c", recovered);
+    update_queue("restore", None).await;
+    // Only now, waiters polling for the restart must not see the old gids
+    ARIA2C_ALIVE.store(true, Ordering::SeqCst);
+    if let Err(e) = bandwidth::apply().await {
+        log::warn!("Failed to apply bandwidth limits: {}", e);
+    }
+    ARIA2C_GENERATION.send_modify(|generation| *generation += 1);
+    get_window().emit("aria2c", json!({ "state": "running" })).unwrap();
+    Ok(())
+}
+
+pub fn alive() -> bool {
+    ARIA2C_ALIVE.load(Ordering::SeqCst)
+}
+
 pub fn kill() -> Result<(), String> {
+    ARIA2C_EXITING.store(true, Ordering::SeqCst);
     if let Some(sc) = ARIA2C_CHILD.write().unwrap().take() {
         sc.kill().map_err(|e| e.to_string())?;
     }
     Ok(())
 }
 
+async fn reconcile(mut info: VideoInfo) -> Result<VideoInfo, String> {
+    for task in info.tasks.iter_mut() {
+        task.gid = downloader(&task.backend).restore(&task.gid, &task.url, &task.path).await?;
+    }
+    refresh_gids(&mut info);
+    Ok(info)
+}
+
+fn refresh_gids(info: &mut VideoInfo) {
+    let vgid = info.tasks.iter().find(|t| t.file_type == "video").map(|t| &*t.gid).unwrap_or_default();
+    let agid = info.tasks.iter().find(|t| t.file_type == "audio").map(|t| &*t.gid).unwrap_or_default();
+    info.gid = json!({"vgid": vgid, "agid": agid});
+}
+
+pub async fn restore() -> Result<(), String> {
+    let backend = CONFIG.read().unwrap().downloader.clone();
+    // Carry on regardless, tasks that can't be re-added fail on their own
+    // instead of holding a slot in the doing queue forever
+    if let Err(e) = downloader(&backend).ready().await {
+        log::warn!("The {} downloader isn't ready, restoring the queue without it: {}", backend, e);
+    }
+    if alive() {
+        if let Err(e) = send_proxy_credentials().await {
+            log::warn!("Failed to pass the proxy credentials to aria2c: {}", e);
+        }
+    }
+    let waiting = WAITING_QUEUE.lock().await.drain(..).collect::<Vec<_>>();
+    let doing = DOING_QUEUE.lock().await.drain(..).collect::<Vec<_>>();
+    let mut restored_waiting = VecDeque::new();
+    let mut restored_doing = VecDeque::new();
+    for info in waiting {
+        restored_waiting.push_back(reconcile(info.clone()).await.unwrap_or_else(|e| {
+            log::warn!("Failed to restore {}: {}", info.display_name, e); info
+        }));
+    }
+    for info in doing {
+        restored_doing.push_back(reconcile(info.clone()).await.unwrap_or_else(|e| {
+            log::warn!("Failed to restore {}: {}", info.display_name, e); info
+        }));
+    }
+    if !restored_waiting.is_empty() || !restored_doing.is_empty() {
+        log::info!("Restored {} waiting and {} doing tasks", restored_waiting.len(), restored_doing.len());
+    }
+    for info in restored_waiting.iter().chain(restored_doing.iter()) {
+        if let Err(e) = diskspace::reserve(info, false).await {
+            log::warn!("Failed to check disk space for {}: {}", info.display_name, e);
+        }
+    }
+    let known = restored_waiting.iter().chain(restored_doing.iter())
+        .flat_map(|info| info.tasks.iter().map(|task| task.gid.clone()))
+        .collect::<HashSet<_>>();
+    if alive() {
+        if let Err(e) = remove_orphans(&known).await {
+            log::warn!("Failed to clean up aria2's session: {}", e);
+        }
+    }
+    *WAITING_QUEUE.lock().await = restored_waiting;
+    *DOING_QUEUE.lock().await = restored_doing.clone();
+    update_queue("restore", None).await;
+    for info in restored_doing {
+        spawn_download(get_window(), info);
+    }
+    Ok(())
+}
+
+// aria2 reloads its session file on start, which can still hold downloads
+// cancelled shortly before the last exit. No task would ever pick them up.
+async fn remove_orphans(known: &HashSet<String>) -> Result<(), String> {
+    let mut statuses = aria2rpc::tell_active().await.map_err(|e| e.to_string())?;
+    let mut offset = 0;
+    loop {
+        let page = aria2rpc::tell_waiting(offset, 1000).await.map_err(|e| e.to_string())?;
+        if page.is_empty() { break; }
+        offset += page.len();
+        statuses.extend(page);
+    }
+    for status in statuses.iter().filter(|status| !known.contains(&status.gid)) {
+        log::info!("Removing {} left over from the last session", status.gid);
+        if let Err(e) = downloader("aria2").remove(&status.gid).await {
+            log::warn!("Failed to remove {}: {}", status.gid, e);
+        }
+    }
+    Ok(())
+}
+
 #[tauri::command]
-pub async fn push_back_queue(queue_info: QueueInfo) -> Result<Value, String> {
+pub async fn push_back_queue(mut queue_info: QueueInfo) -> Result<Value, String> {
     let mut tasks = vec![];
-    let client = init_client().await.map_err(|e| handle_err(e))?;
-    let ss_dir = &queue_info.ss_title;
+    {
+        let config = CONFIG.read().unwrap();
+        if queue_info.container.is_empty() { queue_info.container = config.container.clone(); }
+        if queue_info.audio_mode.is_empty() { queue_info.audio_mode = config.audio_mode.clone(); }
+    }
+    if !queue_info.urls.video.is_empty() && !queue_info.urls.audio.is_empty() {
+        ffmpeg::plan(&queue_info).map_err(handle_err)?;
+    }
+    let output_path = {
+        let config = CONFIG.read().unwrap();
+        template::render(&config.filename_template, &config.down_dir, &queue_info).map_err(handle_err)?
+    };
+    let output_path = match conflict::resolve(output_path, &queue_info).await.map_err(handle_err)? {
+        Resolution::Write(path) => path,
+        Resolution::Skip(reason) => {
+            log::info!("Skipping {}: {}", queue_info.display_name, reason);
+            return Ok(json!({ "skipped": reason }));
+        }
+    };
     let display_name = &queue_info.display_name;
     let urls = queue_info.urls.clone();
+    let backend = CONFIG.read().unwrap().downloader.clone();
     let action = if !urls.video.is_empty() && !urls.audio.is_empty() { "media" }
         else if !urls.video.is_empty() { "video" } else { "audio" }.into();
     
@@ -134,221 +593,678 @@ pub async fn push_back_queue(queue_info: QueueInfo) -> Result<Value, String> {
         let purl = reqwest::Url::parse(&url[0]).map_err(|e| handle_err(e))?;
         let filename = purl.path_segments().unwrap().last().unwrap();
         let path = CONFIG.read().unwrap().temp_dir.join("com.btjawa.bilitools").join(format!("{}_{}", queue_info.time, filename)).join(filename);
-        let init_payload = json!({
-            "jsonrpc": "2.0",
-            "method": "aria2.addUri",
-            "id": "1",
-            "params": [
-                format!("token:{}", *SECRET.read().unwrap()),
-                url,
-                {
-                    "dir": path.parent().unwrap().to_str().unwrap(),
-                    "out": path.file_name().unwrap().to_str().unwrap()
-                }
-            ]
-        });
-        let response = client
-            .post(format!("http://localhost:{}/jsonrpc", ARIA2C_PORT.read().unwrap()))
-            .json(&init_payload)
-            .send().await.map_err(|e| handle_err(e))?;
-
-        let body: Value = response.json().await.map_err(|e| handle_err(e))?;
-        let gid = body["result"].as_str().unwrap().to_string();
-        handle_download(gid.clone(), "pause").await.map_err(|e| handle_err(e))?;
+        let gid = downloader(&backend).add(&url, &path).await.map_err(handle_err)?;
         tasks.push(DownloadTask {
             gid, url, path,
             display_name: display_name.clone(), 
-            file_type: file_type.to_string()
+            file_type: file_type.to_string(),
+            backend: backend.clone()
         });
     }
     let vgid = tasks.iter().find(|t| t.file_type == "video").map(|t| &*t.gid).unwrap_or_default();
     let agid = tasks.iter().find(|t| t.file_type == "audio").map(|t| &*t.gid).unwrap_or_default();
     let gids: Value = json!({"vgid": vgid, "agid": agid});
     let info = VideoInfo {
+        id: rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16).map(char::from)
+            .collect(),
         gid: gids.clone(),
         display_name: display_name.clone(),
         video_path: tasks.iter().find(|t| t.file_type == "video").map(|t| t.path.clone()).unwrap_or_default(),
         audio_path: tasks.iter().find(|t| t.file_type == "audio").map(|t| t.path.clone()).unwrap_or_default(),
-        output_path: CONFIG.read().unwrap().down_dir.join(ss_dir.clone()),
-        tasks, action, queue_info
+        output_path,
+        tasks, action, queue_info,
+        paused: false,
+        failure: None,
+        priority: 0
     };
-    update_queue("push", Some(info), None).await;
+    if let Err(e) = diskspace::reserve(&info, true).await {
+        for task in &info.tasks {
+            if let Err(e) = downloader(&task.backend).remove(&task.gid).await {
+                log::warn!("Failed to remove {}: {}", task.gid, e);
+            }
+        }
+        return Err(handle_err(e));
+    }
+    update_queue("push", Some(info)).await;
     Ok(gids)
 }
 
 #[tauri::command]
 pub async fn handle_download(gid: String, action: &str) -> Result<Value, String> {
-    let client = init_client().await.map_err(|e| handle_err(e))?;
-    let payload = json!({
-        "jsonrpc": "2.0",
-        "method": format!("aria2.{}", action),
-        "id": "1",
-        "params": [format!("token:{}", *SECRET.read().unwrap()), gid]
-    });
-    let resp = client
-        .post(format!("http://localhost:{}/jsonrpc", ARIA2C_PORT.read().unwrap()))
-        .json(&payload)
-        .send().await.map_err(|e| handle_err(e))?;
-
-    let resp_data: Value = resp.json().await.map_err(|e| handle_err(e))?;
-    Ok(json!(resp_data))
+    aria2rpc::call(&format!("aria2.{}", action), vec![json!(gid)]).await
+        .map_err(handle_err)
 }
 
 #[tauri::command]
-pub async fn process_queue(window: WebviewWindow, date: String) -> Result<(), String> {
+pub async fn process_queue() -> Result<Value, String> {
     log::info!("Processing queue...");
-    let mut waiting_len = { WAITING_QUEUE.lock().await.len() as i64 };
+    SCHEDULER_RUNNING.store(true, Ordering::SeqCst);
+    reschedule();
+    Ok(scheduler_state().await)
+}
+
+#[tauri::command]
+pub async fn stop_queue() -> Result<Value, String> {
+    log::info!("Stopping queue, running tasks will finish...");
+    SCHEDULER_RUNNING.store(false, Ordering::SeqCst);
+    reschedule();
+    Ok(scheduler_state().await)
+}
+
+#[tauri::command]
+pub async fn get_scheduler_state() -> Result<Value, String> {
+    Ok(scheduler_state().await)
+}
+
+async fn scheduler_state() -> Value {
+    let doing = DOING_QUEUE.lock().await.len();
+    let waiting = WAITING_QUEUE.lock().await.len();
+    let running = SCHEDULER_RUNNING.load(Ordering::SeqCst);
+    json!({
+        "running": running,
+        "idle": !running || (doing == 0 && waiting == 0),
+        "doing": doing,
+        "waiting": waiting,
+        "max_conc": CONFIG.read().unwrap().max_conc
+    })
+}
+
+async fn queued_aria2_tasks() -> Vec<DownloadTask> {
+    let waiting_queue = WAITING_QUEUE.lock().await;
+    let doing_queue = DOING_QUEUE.lock().await;
+    waiting_queue.iter().chain(doing_queue.iter())
+        .flat_map(|info| info.tasks.iter())
+        .filter(|t| t.backend != "native")
+        .cloned()
+        .collect()
+}
+
+pub async fn active_aria2_gids() -> Vec<String> {
+    DOING_QUEUE.lock().await.iter()
+        .flat_map(|info| info.tasks.iter())
+        .filter(|t| t.backend != "native")
+        .map(|t| t.gid.clone())
+        .collect()
+}
+
+// Streams download one after another, so resuming only wakes the first
+// unfinished one. The rest are started once process_download reaches them.
+async fn set_streams_paused(info: &VideoInfo, paused: bool) {
+    for task in &info.tasks {
+        let downloader = downloader(&task.backend);
+        let result = if paused {
+            downloader.pause(&task.gid).await
+        } else {
+            match downloader.status(&task.gid).await {
+                Ok(status) if status.status == Aria2State::Complete => continue,
+                Ok(status) if status.status == Aria2State::Paused => downloader.start(&task.gid).await,
+                Ok(_) => Ok(()),
+                Err(e) => Err(e)
+            }
+        };
+        if let Err(e) = result {
+            log::warn!("Failed to change state of {}: {}", task.gid, e);
+        }
+        if !paused { break; }
+    }
+}
+
+pub async fn hold_doing(hold: bool) {
+    let doing = DOING_QUEUE.lock().await.clone();
+    for info in doing.iter().filter(|info| !info.paused) {
+        set_streams_paused(info, hold).await;
+    }
+}
+
+pub fn reschedule() {
+    SCHEDULER_NOTIFY.notify_one();
+}
+
+pub async fn scheduler() {
     loop {
-        if waiting_len == 0 { break; }
-        let max_conc = CONFIG.read().unwrap().max_conc;
-        let doing_len = { DOING_QUEUE.lock().await.len() };
-        for _ in 0..max_conc.saturating_sub(doing_len) {
-            if let Some(info) = update_queue("waiting", None, Some(&date)).await {
-                let window_clone = window.clone();
-                async_runtime::spawn(async move {
-                    process_download(&window_clone, &info).await.unwrap();
-                });
+        if SCHEDULER_RUNNING.load(Ordering::SeqCst) && bandwidth::window_open() {
+            loop {
+                let max_conc = CONFIG.read().unwrap().max_conc;
+                if DOING_QUEUE.lock().await.len() >= max_conc { break; }
+                match update_queue("waiting", None).await {
+                    Some(info) => spawn_download(get_window(), info),
+                    None => break
+                }
             }
         }
-        waiting_len -= 1;
-        DOWNLOAD_COMPLETED_NOTIFY.notified().await;
+        get_window().emit("scheduler-state", scheduler_state().await).unwrap();
+        SCHEDULER_NOTIFY.notified().await;
     }
-    Ok(())
 }
 
-async fn process_download(window: &WebviewWindow, info: &VideoInfo) -> Result<(), String> {
-    fs::create_dir_all(&info.output_path.parent().unwrap()).map_err(|e| handle_err(e))?;
-    let action = &info.action;
-    for task in &info.tasks {
-        if let Ok(_) = download_file(&window, &task, &info.gid).await {
-            if *action == "only" {
-                fs::rename(&task.path, &info.output_path)
-                .map_err(|e| handle_err(e))?;
+fn spawn_download(window: WebviewWindow, info: VideoInfo) {
+    async_runtime::spawn(async move {
+        if let Err(e) = process_download(&window, &info).await {
+            if is_cancelled(&info.id) {
+                log::info!("Cancelled {}", info.display_name);
+            } else {
+                log::error!("Failed to download {}: {}", info.display_name, e);
+                update_queue("failed", Some(VideoInfo { failure: Some(e), ..info.clone() })).await;
+            }
+        }
+        // Also when it finished before a cancel could stop it. The task has
+        // left the doing queue by now, so no cancel can mark it again.
+        CANCELLED.write().unwrap().remove(&info.id);
+    });
+}
+
+pub fn is_cancelled(id: &str) -> bool {
+    CANCELLED.read().unwrap().contains(id)
+}
+
+async fn download_with_retry(window: &WebviewWindow, info: &mut VideoInfo, index: usize) -> Result<(), TaskFailure> {
+    let (max_retries, retry_backoff) = {
+        let config = CONFIG.read().unwrap();
+        (config.max_retries, config.retry_backoff)
+    };
+    let mut attempt = 0;
+    loop {
+        let task = info.tasks[index].clone();
+        let e = match download_file(window, info, &task).await {
+            Ok(_) => return Ok(()),
+            Err(e) => e
+        };
+        // The supervisor backs off between restarts, so no delay here, but an
+        // aria2c that keeps dying must not keep the task going forever
+        if e.kind == FailureKind::Restarted && !is_cancelled(&info.id) && attempt < max_retries {
+            attempt += 1;
+            log::warn!("{} was interrupted by an aria2c restart, retry {}/{}", task.display_name, attempt, max_retries);
+            let queued = find_task(&mut *DOING_QUEUE.lock().await, &info.id).cloned();
+            if let Some(queued) = queued {
+                info.tasks = queued.tasks;
+                info.gid = queued.gid;
             }
+            continue;
+        }
+        if is_cancelled(&info.id) || !e.kind.retryable() || attempt >= max_retries {
+            return Err(e);
+        }
+        attempt += 1;
+        let delay = retry_backoff.saturating_mul(1 << (attempt - 1).min(10));
+        log::warn!("{} failed ({:?}: {}), retry {}/{} in {}s",
+            task.display_name, e.kind, e.message, attempt, max_retries, delay);
+        sleep(Duration::from_secs(delay)).await;
+        if is_cancelled(&info.id) {
+            return Err(e);
+        }
+        if let Err(e) = downloader(&task.backend).remove(&task.gid).await {
+            log::warn!("Failed to remove result of {}: {}", task.gid, e);
+        }
+        let fresh_urls = match e.kind {
+            FailureKind::Expired => playurl::resolve(&info.queue_info).await
+                .map_err(|e| log::warn!("Failed to refresh links of {}: {}", info.display_name, e)).ok(),
+            _ => None
+        };
+        let task = &mut info.tasks[index];
+        match fresh_urls.as_ref().map(|urls| if task.file_type == "video" { &urls.video } else { &urls.audio }) {
+            Some(urls) if !urls.is_empty() => task.url = urls.clone(),
+            _ => task.url.rotate_left(1)
         }
+        if let Some(urls) = fresh_urls {
+            info.queue_info.urls = urls;
+        }
+        task.gid = downloader(&task.backend).add(&task.url, &task.path).await.map_err(handle_err)?;
+        refresh_gids(info);
+        update_queue("update", Some(info.clone())).await;
+    }
+}
+
+async fn refresh_urls(info: &mut VideoInfo) -> Result<(), String> {
+    if !info.tasks.iter().any(|t| t.url.first().is_some_and(|url| playurl::is_expired(url))) {
+        return Ok(());
+    }
+    log::info!("Links of {} are stale, refreshing...", info.display_name);
+    let urls = playurl::resolve(&info.queue_info).await?;
+    for task in info.tasks.iter_mut() {
+        let fresh = if task.file_type == "video" { &urls.video } else { &urls.audio };
+        if fresh.is_empty() { continue; }
+        downloader(&task.backend).change_uris(&task.gid, &task.url, fresh).await?;
+        task.url = fresh.clone();
+    }
+    info.queue_info.urls = urls;
+    update_queue("update", Some(info.clone())).await;
+    Ok(())
+}
+
+async fn process_download(window: &WebviewWindow, info: &VideoInfo) -> Result<(), TaskFailure> {
+    fs::create_dir_all(&info.output_path.parent().unwrap()).map_err(|e| handle_err(e))?;
+    let mut info = info.clone();
+    if let Err(e) = refresh_urls(&mut info).await {
+        log::warn!("Failed to refresh links of {}: {}", info.display_name, e);
+    }
+    for index in 0..info.tasks.len() {
+        download_with_retry(window, &mut info, index).await?;
+    }
+    if is_cancelled(&info.id) {
+        return Err(format!("{} was cancelled", info.display_name).into());
     }
-    if *action == "multi" {
+    // Something else may have written the file since this task was queued
+    let policy = CONFIG.read().unwrap().on_conflict.clone();
+    if info.output_path.exists() && policy == "skip" {
+        let reason = format!("{} already exists", info.output_path.display());
+        log::info!("Skipping {}: {}", info.display_name, reason);
+        clean_temp_files(&info);
+        update_queue("cancel", Some(info.clone())).await;
+        get_window().emit("skipped", json!({ "id": info.id, "reason": reason })).unwrap();
+        return Ok(());
+    }
+    if info.output_path.exists() && policy == "rename" {
+        info.output_path = conflict::unique_path(&info.output_path).await;
+        log::info!("{} appeared meanwhile, writing to {} instead", info.display_name, info.output_path.display());
+        update_queue("update", Some(info.clone())).await;
+    }
+    if info.action == "media" {
         ffmpeg::init_merge(&window, &info).await
+        .map_err(|e| if is_cancelled(&info.id) { e } else { handle_err(e) })?;
+    } else if let Some(task) = info.tasks.first() {
+        finalize::commit(&task.path, &info.output_path)
         .map_err(|e| handle_err(e))?;
     }
-    update_queue("doing", None, None).await;
+    update_queue("doing", Some(info.clone())).await;
+    Ok(())
+}
+
+fn find_task<'a>(queue: &'a mut VecDeque<VideoInfo>, id: &str) -> Option<&'a mut VideoInfo> {
+    queue.iter_mut().find(|info| info.id == id)
+}
+
+async fn set_paused(id: String, paused: bool) -> Result<VideoInfo, String> {
+    let (info, doing) = {
+        let mut waiting_queue = WAITING_QUEUE.lock().await;
+        let mut doing_queue = DOING_QUEUE.lock().await;
+        let doing = find_task(&mut doing_queue, &id).is_some();
+        let info = find_task(&mut doing_queue, &id)
+            .or(find_task(&mut waiting_queue, &id))
+            .ok_or(format!("No active task with id {}", id))?;
+        info.paused = paused;
+        (info.clone(), doing)
+    };
+    if doing {
+        set_streams_paused(&info, paused).await;
+    }
+    update_queue("update", Some(info.clone())).await;
+    Ok(info)
+}
+
+fn clean_temp_files(info: &VideoInfo) {
+    for task in &info.tasks {
+        let control_files = ["aria2", "native"].map(|ext| PathBuf::from(format!("{}.{}", task.path.to_string_lossy(), ext)));
+        for path in [&task.path].into_iter().chain(control_files.iter()) {
+            if path.exists() { let _ = fs::remove_file(path); }
+        }
+        if let Some(parent) = task.path.parent() {
+            let _ = fs::remove_dir(parent);
+        }
+    }
+}
+
+async fn cancel(id: &str) -> Result<Option<VideoInfo>, String> {
+    let info = {
+        let waiting_queue = WAITING_QUEUE.lock().await;
+        let doing_queue = DOING_QUEUE.lock().await;
+        match doing_queue.iter().find(|info| info.id == id) {
+            Some(info) => {
+                // Marked while the task is surely still running, see spawn_download
+                CANCELLED.write().unwrap().insert(info.id.clone());
+                Some(info.clone())
+            },
+            None => waiting_queue.iter().find(|info| info.id == id).cloned()
+        }
+    };
+    let Some(info) = info else { return Ok(None) };
+    // A killed merge only ever wrote the .part file, the output may be an older download
+    if ffmpeg::cancel(&info.id) {
+        let _ = fs::remove_file(finalize::part_path(&info.output_path));
+    }
+    discard(&info).await;
+    update_queue("cancel", Some(info.clone())).await;
+    Ok(Some(info))
+}
+
+// Drops the streams of a task that won't run again, with whatever they downloaded
+async fn discard(info: &VideoInfo) {
+    for task in &info.tasks {
+        if let Err(e) = downloader(&task.backend).remove(&task.gid).await {
+            log::warn!("Failed to remove {}: {}", task.gid, e);
+        }
+    }
+    clean_temp_files(info);
+}
+
+#[tauri::command]
+pub async fn pause_task(id: String) -> Result<VideoInfo, String> {
+    set_paused(id, true).await.map_err(handle_err)
+}
+
+#[tauri::command]
+pub async fn resume_task(id: String) -> Result<VideoInfo, String> {
+    set_paused(id, false).await.map_err(handle_err)
+}
+
+#[tauri::command]
+pub async fn cancel_task(id: String) -> Result<VideoInfo, String> {
+    cancel(&id).await.map_err(handle_err)?
+        .ok_or_else(|| handle_err(format!("No active task with id {}", id)))
+}
+
+#[tauri::command]
+pub async fn remove_task(id: String) -> Result<(), String> {
+    if cancel(&id).await.map_err(handle_err)?.is_some() { return Ok(()); }
+    let failed = FAILED_QUEUE.lock().await.iter().find(|info| info.id == id).cloned();
+    if let Some(info) = &failed {
+        discard(info).await;
+    }
+    update_queue("remove", Some(failed.unwrap_or(VideoInfo { id, ..Default::default() }))).await;
     Ok(())
 }
 
-fn add_timestamp(mut info: VideoInfo, date: &String) -> VideoInfo {
-    let mut output_path_str = info.output_path.to_string_lossy().to_string();
-    output_path_str += &format!("_{}", date);
-    info.output_path = PathBuf::from(output_path_str).join(&info.display_name);
-    info
+#[tauri::command]
+pub async fn move_task(id: String, position: &str, index: Option<usize>) -> Result<(), String> {
+    {
+        let mut waiting_queue = WAITING_QUEUE.lock().await;
+        let from = waiting_queue.iter().position(|i| i.id == id)
+            .ok_or_else(|| handle_err(format!("No waiting task with id {}", id)))?;
+        let last = waiting_queue.len() - 1;
+        let to = match position {
+            "top" => 0,
+            "bottom" => last,
+            "index" => index.ok_or_else(|| handle_err("Missing index"))?.min(last),
+            _ => return Err(handle_err(format!("Unknown position {}", position)))
+        };
+        let mut info = waiting_queue.remove(from).unwrap();
+        // Take on a priority between the new neighbours, so the spot holds
+        // once update_queue sorts the queue by priority
+        let upper = to.checked_sub(1).and_then(|i| waiting_queue.get(i)).map_or(i32::MAX, |i| i.priority);
+        let lower = waiting_queue.get(to).map_or(i32::MIN, |i| i.priority);
+        info.priority = info.priority.max(lower).min(upper);
+        waiting_queue.insert(to, info);
+    }
+    update_queue("reorder", None).await;
+    Ok(())
 }
 
-async fn update_queue(action: &str, info: Option<VideoInfo>, date: Option<&String>) -> Option<VideoInfo> {
+#[tauri::command]
+pub async fn set_priority(id: String, priority: i32) -> Result<VideoInfo, String> {
+    let info = {
+        let mut waiting_queue = WAITING_QUEUE.lock().await;
+        let info = find_task(&mut waiting_queue, &id)
+            .ok_or_else(|| handle_err(format!("No waiting task with id {}", id)))?;
+        info.priority = priority;
+        info.clone()
+    };
+    update_queue("update", Some(info.clone())).await;
+    Ok(info)
+}
+
+pub async fn update_queue(action: &str, info: Option<VideoInfo>) -> Option<VideoInfo> {
     let mut waiting_queue = WAITING_QUEUE.lock().await;
     let mut doing_queue = DOING_QUEUE.lock().await;
     let mut complete_queue = COMPLETE_QUEUE.lock().await;
+    let mut failed_queue = FAILED_QUEUE.lock().await;
     let mut result_info: Option<VideoInfo> = None;
     match action {
         "push" => {
-            if let Some(org_info) = info {
-                let info = if let Some(date) = date {
-                    add_timestamp(org_info, date)
-                } else { org_info };
+            if let Some(info) = info {
                 waiting_queue.push_back(info.clone());
                 result_info = Some(info);
             }
         },
         "waiting" => {
-            if let Some(org_info) = waiting_queue.pop_front() {
-                let info = if let Some(date) = date {
-                    add_timestamp(org_info, date)
-                } else { org_info };
+            let index = waiting_queue.iter().enumerate()
+                .filter(|(_, i)| !i.paused)
+                .max_by_key(|(index, i)| (i.priority, Reverse(*index)))
+                .map(|(index, _)| index);
+            if let Some(info) = index.and_then(|index| waiting_queue.remove(index)) {
                 doing_queue.push_back(info.clone());
                 result_info = Some(info);
             }
         },
         "doing" => {
-            if let Some(org_info) = doing_queue.pop_front() {
-                let info = if let Some(date) = date {
-                    add_timestamp(org_info, date)
-                } else { org_info };
-                downloads::insert(info.clone()).await.map_err(|e| handle_err(e)).unwrap();
+            let index = info.and_then(|info| doing_queue.iter().position(|i| i.id == info.id));
+            if let Some(info) = index.and_then(|index| doing_queue.remove(index)) {
+                diskspace::release(&info.id);
+                if let Err(e) = downloads::insert(info.clone()).await {
+                    handle_err(e);
+                }
                 complete_queue.push_back(info.clone());
-                log::info!("Finished. Notifying process_queue...");
-                DOWNLOAD_COMPLETED_NOTIFY.notify_one();
+                log::info!("Finished {}", info.display_name);
+                result_info = Some(info);
+            }
+        },
+        "update" => {
+            if let Some(info) = info {
+                if let Some(entry) = find_task(&mut waiting_queue, &info.id)
+                    .or(find_task(&mut doing_queue, &info.id)) {
+                    *entry = info.clone();
+                }
+                result_info = Some(info);
+            }
+        },
+        "failed" => {
+            if let Some(info) = info {
+                diskspace::release(&info.id);
+                doing_queue.retain(|i| i.id != info.id);
+                failed_queue.push_back(info.clone());
+                result_info = Some(info);
+            }
+        },
+        "cancel" | "remove" => {
+            if let Some(info) = info {
+                diskspace::release(&info.id);
+                waiting_queue.retain(|i| i.id != info.id);
+                failed_queue.retain(|i| i.id != info.id);
+                doing_queue.retain(|i| i.id != info.id);
+                if action == "remove" {
+                    if let Some(index) = complete_queue.iter().position(|i| i.id == info.id) {
+                        let removed = complete_queue.remove(index).unwrap();
+                        if let Err(e) = downloads::delete(&removed.id).await {
+                            handle_err(e);
+                        }
+                    }
+                }
                 result_info = Some(info);
             }
         },
         _ => {}
     }
+    if action != "waiting" {
+        reschedule();
+    }
+    // Stable, so equal priorities keep their order and the list is the run order
+    waiting_queue.make_contiguous().sort_by_key(|i| Reverse(i.priority));
+    if let Err(e) = queue::save(&waiting_queue, &doing_queue, &failed_queue).await {
+        handle_err(e);
+    }
     let queue = json!({
         "waiting": waiting_queue.iter().map(|info| json!(info)).collect::<Vec<_>>(),
         "doing": doing_queue.iter().map(|info| json!(info)).collect::<Vec<_>>(),
         "complete": complete_queue.iter().map(|info| json!(info)).collect::<Vec<_>>(),
+        "failed": failed_queue.iter().map(|info| json!(info)).collect::<Vec<_>>(),
     });
-    crate::get_window().emit("download-queue", queue).unwrap();
+    get_window().emit("download-queue", queue).unwrap();
     result_info
 }
 
-async fn download_file(window: &WebviewWindow, task: &DownloadTask, gid: &Value) -> Result<String, String> {
-    log::info!("Start download: {}", &task.display_name);
-    let client = init_client().await.map_err(|e| handle_err(e))?;
-    handle_download(task.gid.clone(), "unpause").await.map_err(|e| handle_err(e))?;
+fn emit_progress(window: &WebviewWindow, task: &DownloadTask, gid: &Value, status: &Aria2Status) -> String {
+    let completed_length = status.completed_length as f64;
+    let total_length = status.total_length as f64;
+    let download_speed = status.download_speed as f64;
+    let downloaded = completed_length / 1024.0 / 1024.0;
+    let speed = download_speed / 1024.0 / 1024.0;
+    let progress = if total_length > 0.0 {
+        (completed_length / total_length) * 100.0
+    } else { 0.0 };
+    let remaining = if download_speed > 0.0 {
+        (total_length - completed_length) / download_speed
+    } else { 0.0 };
+    let is_paused = status.status == Aria2State::Paused;
+    let formatted_values = json!({
+        "remaining": if is_paused { "已暂停".to_string() } else { format!("{:.2} s", remaining) },
+        "downloaded": if is_paused { "已暂停".to_string() } else { format!("{:.2} MB", downloaded) },
+        "speed": if is_paused { "已暂停".to_string() } else { format!("{:.2} MB/s", speed) },
+        "progress": format!("{:.2}%", progress),
+        "display_name": task.display_name,
+        "gid": gid,
+        "file_type": task.file_type,
+        "type": "download".to_string()
+    });
+    let formatted_array: Vec<String> = formatted_values.as_object().unwrap()
+    .iter().map(|(_key, value)| {
+        match value { Value::String(s) => s.clone(),
+        _ => format!("{}", value) }
+    }).collect();
+    window.emit("progress", &formatted_values).unwrap();
+    formatted_array.join(" | ")
+}
+
+pub async fn progress_sampler() {
+    let mut ticker = interval(Duration::from_millis(500));
     let mut last_log_time = Instant::now();
     loop {
-        let status_payload = json!({
-            "jsonrpc": "2.0",
-            "method": "aria2.tellStatus",
-            "id": "1",
-            "params": [format!("token:{}", *SECRET.read().unwrap()), task.gid]
-        });
-        let status_resp = client
-            .post(format!("http://localhost:{}/jsonrpc", ARIA2C_PORT.read().unwrap()))
-            .json(&status_payload)
-            .send().await.map_err(|e| handle_err(e))?;
-
-        let status_resp_data: Value = status_resp.json().await.map_err(|e| handle_err(e))?;
-        if let Some(e) = status_resp_data["error"].as_object() {
-            let error_code = e.get("code").and_then(|c| c.as_i64()).unwrap();
-            let error_message = e.get("message").and_then(|m| m.as_str()).unwrap();
-            let err = format!("Error code {}: {}", error_code, error_message);
-            handle_err(&err);
-            return Err(err);
-        }
-        let completed_length = status_resp_data["result"]["completedLength"].as_str().unwrap().parse::<f64>().unwrap();
-        let total_length = status_resp_data["result"]["totalLength"].as_str().unwrap().parse::<f64>().unwrap();
-        let download_speed = status_resp_data["result"]["downloadSpeed"].as_str().unwrap().parse::<f64>().unwrap();
-        let downloaded = completed_length as f64 / 1024.0 / 1024.0;
-        let speed = download_speed as f64 / 1024.0 / 1024.0;
-        let progress = if total_length > 0.0 {
-            (completed_length as f64 / total_length as f64) * 100.0
-        } else { 0.0 };
-        let remaining = if download_speed > 0.0 {
-            (total_length - completed_length) as f64 / download_speed as f64
-        } else { 0.0 };
-        let status = status_resp_data["result"]["status"].as_str();
-        let is_paused = status == Some("paused");
-        let formatted_values = json!({
-            "remaining": if is_paused { "已暂停".to_string() } else { format!("{:.2} s", remaining) },
-            "downloaded": if is_paused { "已暂停".to_string() } else { format!("{:.2} MB", downloaded) },
-            "speed": if is_paused { "已暂停".to_string() } else { format!("{:.2} MB/s", speed) },
-            "progress": format!("{:.2}%", progress),
-            "display_name": task.display_name,
-            "gid": gid,
-            "file_type": task.file_type,
-            "type": "download".to_string()
-        });
-        let formatted_array: Vec<String> = formatted_values.as_object().unwrap()
-        .iter().map(|(_key, value)| {
-            match value { Value::String(s) => format!("{}", s),
-            _ => format!("{}", value) }
-        }).collect();
+        ticker.tick().await;
+        let sampled = SAMPLED_TASKS.read().unwrap().clone();
+        if sampled.is_empty() { continue; }
+        let mut by_backend: HashMap<String, Vec<String>> = HashMap::new();
+        for (gid, (task, _)) in &sampled {
+            by_backend.entry(task.backend.clone()).or_default().push(gid.clone());
+        }
+        let window = get_window();
+        let mut lines = vec![];
+        for (backend, gids) in by_backend {
+            let statuses = match downloader(&backend).status_many(&gids).await {
+                Ok(statuses) => statuses,
+                Err(e) => { log::warn!("Failed to sample progress: {}", e); continue; }
+            };
+            for (gid, status) in gids.iter().zip(statuses) {
+                let (task, parent_gid) = &sampled[gid];
+                match status {
+                    Ok(status) => lines.push(emit_progress(&window, task, parent_gid, &status)),
+                    Err(e) => log::warn!("Failed to sample {}: {}", gid, e),
+                }
+            }
+        }
         if last_log_time.elapsed() >= Duration::from_secs(1) {
-            log::info!("{:?}", formatted_array.join(" | "));
+            for line in lines { log::info!("{:?}", line); }
             last_log_time = Instant::now();
         }
-        window.emit("progress", &formatted_values).unwrap();
-        if status == Some("complete") { break; }
-        sleep(Duration::from_millis(500)).await;
     }
+}
+
+fn set_sampling(task: &DownloadTask, gid: &Value, sampling: bool) {
+    let mut sampled = SAMPLED_TASKS.write().unwrap();
+    if sampling {
+        sampled.insert(task.gid.clone(), (task.clone(), gid.clone()));
+    } else {
+        sampled.remove(&task.gid);
+    }
+}
+
+fn status_to_result(task: &DownloadTask, status: &Aria2Status) -> Option<Result<(), TaskFailure>> {
+    match status.status {
+        Aria2State::Complete => Some(Ok(())),
+        Aria2State::Error => {
+            let message = status.error_message.clone().unwrap_or_default();
+            Some(Err(TaskFailure {
+                kind: FailureKind::classify(status.error_code, &message),
+                message: format!("Error code {}: {}", status.error_code.unwrap_or(-1), message)
+            }))
+        },
+        Aria2State::Removed => Some(Err(TaskFailure {
+            kind: FailureKind::Removed,
+            message: format!("{} was removed", task.gid)
+        })),
+        _ => None
+    }
+}
+
+async fn wait_for_file(window: &WebviewWindow, task: &DownloadTask, gid: &Value, start: bool) -> Result<(), TaskFailure> {
+    if task.backend == "native" {
+        return watch_file(window, task, gid, start).await;
+    }
+    let restarted = || TaskFailure {
+        kind: FailureKind::Restarted,
+        message: format!("aria2c restarted while downloading {}", task.gid)
+    };
+    let mut generation = ARIA2C_GENERATION.subscribe();
+    select! {
+        result = watch_file(window, task, gid, start) => match result {
+            // The RPC may fail before the supervisor sees the process exit
+            Err(_) if !alive() || aria2rpc::get_global_stat().await.is_err() => {
+                wait_for_restart(&mut generation).await;
+                Err(restarted())
+            },
+            result => result
+        },
+        _ = generation.changed() => Err(restarted())
+    }
+}
+
+// Polls besides waiting for the supervisor, which may never get aria2c back,
+// or the failed RPC was a hiccup of an instance that is still running.
+// download_with_retry bounds how often a task goes through this.
+async fn wait_for_restart(generation: &mut watch::Receiver<usize>) {
+    let deadline = Instant::now() + Duration::from_secs(120);
+    while Instant::now() < deadline {
+        select! {
+            _ = generation.changed() => return,
+            _ = sleep(Duration::from_secs(5)) => {
+                if alive() && aria2rpc::get_global_stat().await.is_ok() { return; }
+            }
+        }
+    }
+}
+
+async fn watch_file(window: &WebviewWindow, task: &DownloadTask, gid: &Value, start: bool) -> Result<(), TaskFailure> {
+    let downloader = downloader(&task.backend);
+    let mut events = downloader.subscribe();
+    let mut status = downloader.status(&task.gid).await?;
+    // A resume may have started it already
+    if start && status.status == Aria2State::Paused {
+        downloader.start(&task.gid).await?;
+        status = downloader.status(&task.gid).await?;
+    }
+    emit_progress(window, task, gid, &status);
+    set_sampling(task, gid, status.status == Aria2State::Active);
+    if let Some(result) = status_to_result(task, &status) {
+        return result;
+    }
+    loop {
+        let kind = match events.recv().await {
+            // Notifications may have been missed, look at the status again
+            Ok(event) if event.kind == Aria2EventKind::Resync => None,
+            Ok(event) if event.gid == task.gid => Some(event.kind),
+            Ok(_) => continue,
+            Err(broadcast::error::RecvError::Lagged(_)) => None,
+            Err(broadcast::error::RecvError::Closed) => return Err(String::from("aria2 notification channel closed").into()),
+        };
+        let status = downloader.status(&task.gid).await?;
+        emit_progress(window, task, gid, &status);
+        set_sampling(task, gid, match kind {
+            Some(Aria2EventKind::Start) => true,
+            Some(Aria2EventKind::Pause) => false,
+            _ => status.status == Aria2State::Active,
+        });
+        if let Some(result) = status_to_result(task, &status) {
+            return result;
+        }
+    }
+}
+
+async fn download_file(window: &WebviewWindow, info: &VideoInfo, task: &DownloadTask) -> Result<String, TaskFailure> {
+    log::info!("Start download: {}", &task.display_name);
+    let start = !info.paused && bandwidth::window_open();
+    let result = wait_for_file(window, task, &info.gid, start).await;
+    set_sampling(task, &info.gid, false);
+    if let Err(e) = &result {
+        if !is_cancelled(&info.id) && e.kind != FailureKind::Restarted { handle_err(e); }
+    }
+    result?;
     Ok(task.display_name.to_string())
-}
\ No newline at end of file
+}
@@ -0,0 +1,307 @@
+use futures_util::StreamExt;
+use lazy_static::lazy_static;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_json::{json, Map, Value};
+use std::fmt;
+use tauri_plugin_http::reqwest::Client;
+use tokio::{sync::broadcast, time::{sleep, Duration}};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+use crate::aria2c::{ARIA2C_PORT, ARIA2C_SECRET};
+
+lazy_static! {
+    static ref RPC_CLIENT: Client = Client::builder().no_proxy().build().unwrap();
+    static ref ARIA2_EVENTS: broadcast::Sender<Aria2Event> = broadcast::channel(256).0;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Aria2Error {
+    Http(String),
+    Parse(String),
+    Rpc { code: i64, message: String },
+}
+
+impl fmt::Display for Aria2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Aria2Error::Http(e) => write!(f, "aria2 RPC request failed: {}", e),
+            Aria2Error::Parse(e) => write!(f, "Malformed aria2 RPC response: {}", e),
+            Aria2Error::Rpc { code, message } => write!(f, "aria2 error code {}: {}", code, message),
+        }
+    }
+}
+
+impl std::error::Error for Aria2Error {}
+
+#[derive(Deserialize, Debug)]
+struct RpcResponse<T> {
+    result: Option<T>,
+    error: Option<RpcErrorObject>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcErrorObject {
+    code: i64,
+    message: String,
+}
+
+fn de_num<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse::<u64>().map_err(serde::de::Error::custom)
+}
+
+fn de_opt_num<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i64>, D::Error> {
+    let s = Option::<String>::deserialize(deserializer)?;
+    s.map(|s| s.parse::<i64>().map_err(serde::de::Error::custom)).transpose()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aria2State {
+    Active,
+    Waiting,
+    Paused,
+    Error,
+    Complete,
+    Removed,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Aria2Status {
+    pub gid: String,
+    pub status: Aria2State,
+    #[serde(deserialize_with = "de_num")]
+    pub total_length: u64,
+    #[serde(deserialize_with = "de_num")]
+    pub completed_length: u64,
+    #[serde(deserialize_with = "de_num")]
+    pub download_speed: u64,
+    #[serde(default, deserialize_with = "de_opt_num")]
+    pub error_code: Option<i64>,
+    #[serde(default)]
+    pub error_message: Option<String>,
+    #[serde(default)]
+    pub dir: Option<String>,
+    #[serde(default)]
+    pub files: Vec<Aria2File>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Aria2File {
+    #[serde(deserialize_with = "de_num")]
+    pub index: u64,
+    pub path: String,
+    #[serde(deserialize_with = "de_num")]
+    pub length: u64,
+    #[serde(deserialize_with = "de_num")]
+    pub completed_length: u64,
+    pub selected: String,
+    #[serde(default)]
+    pub uris: Vec<Aria2Uri>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Aria2Uri {
+    pub uri: String,
+    pub status: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Aria2GlobalStat {
+    #[serde(deserialize_with = "de_num")]
+    pub download_speed: u64,
+    #[serde(deserialize_with = "de_num")]
+    pub upload_speed: u64,
+    #[serde(deserialize_with = "de_num")]
+    pub num_active: u64,
+    #[serde(deserialize_with = "de_num")]
+    pub num_waiting: u64,
+    #[serde(deserialize_with = "de_num")]
+    pub num_stopped: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aria2EventKind {
+    Start,
+    Pause,
+    Stop,
+    Complete,
+    Error,
+    // Sent on every (re)connect without a gid, anything aria2 announced
+    // while the socket was down is lost and has to be re-queried
+    Resync,
+}
+
+#[derive(Clone, Debug)]
+pub struct Aria2Event {
+    pub gid: String,
+    pub kind: Aria2EventKind,
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcNotification {
+    method: String,
+    params: Vec<RpcNotificationParam>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcNotificationParam {
+    gid: String,
+}
+
+pub fn endpoint() -> String {
+    format!("http://localhost:{}/jsonrpc", ARIA2C_PORT.read().unwrap())
+}
+
+fn ws_endpoint() -> String {
+    format!("ws://localhost:{}/jsonrpc", ARIA2C_PORT.read().unwrap())
+}
+
+pub fn subscribe() -> broadcast::Receiver<Aria2Event> {
+    ARIA2_EVENTS.subscribe()
+}
+
+fn parse_notification(text: &str) -> Option<Vec<Aria2Event>> {
+    let notification: RpcNotification = serde_json::from_str(text).ok()?;
+    let kind = match notification.method.as_str() {
+        "aria2.onDownloadStart" => Aria2EventKind::Start,
+        "aria2.onDownloadPause" => Aria2EventKind::Pause,
+        "aria2.onDownloadStop" => Aria2EventKind::Stop,
+        "aria2.onDownloadComplete" | "aria2.onBtDownloadComplete" => Aria2EventKind::Complete,
+        "aria2.onDownloadError" => Aria2EventKind::Error,
+        _ => return None,
+    };
+    Some(notification.params.into_iter()
+        .map(|p| Aria2Event { gid: p.gid, kind }).collect())
+}
+
+pub async fn listen() {
+    loop {
+        match connect_async(ws_endpoint()).await {
+            Ok((mut stream, _)) => {
+                log::info!("Connected to aria2 notifications");
+                let _ = ARIA2_EVENTS.send(Aria2Event { gid: String::new(), kind: Aria2EventKind::Resync });
+                while let Some(message) = stream.next().await {
+                    let text = match message {
+                        Ok(Message::Text(text)) => text,
+                        Ok(Message::Close(_)) | Err(_) => break,
+                        Ok(_) => continue,
+                    };
+                    for event in parse_notification(&text).unwrap_or_default() {
+                        let _ = ARIA2_EVENTS.send(event);
+                    }
+                }
+                log::warn!("aria2 notification stream closed, reconnecting...");
+            },
+            Err(e) => log::warn!("Failed to connect to aria2 notifications: {}", e),
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+}
+
+fn token() -> String {
+    format!("token:{}", *ARIA2C_SECRET.read().unwrap())
+}
+
+fn with_token(params: Vec<Value>) -> Vec<Value> {
+    let mut rpc_params = vec![json!(token())];
+    rpc_params.extend(params);
+    rpc_params
+}
+
+async fn request<T: DeserializeOwned>(method: &str, params: Vec<Value>) -> Result<T, Aria2Error> {
+    let payload = json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "id": "bilitools",
+        "params": params
+    });
+    let resp = RPC_CLIENT.post(endpoint())
+        .json(&payload)
+        .send().await.map_err(|e| Aria2Error::Http(e.to_string()))?;
+    let body: RpcResponse<T> = resp.json().await
+        .map_err(|e| Aria2Error::Parse(e.to_string()))?;
+    if let Some(e) = body.error {
+        return Err(Aria2Error::Rpc { code: e.code, message: e.message });
+    }
+    body.result.ok_or(Aria2Error::Parse(format!("{} returned neither result nor error", method)))
+}
+
+pub async fn call<T: DeserializeOwned>(method: &str, params: Vec<Value>) -> Result<T, Aria2Error> {
+    request(method, with_token(params)).await
+}
+
+pub async fn multicall<T: DeserializeOwned>(calls: Vec<(&str, Vec<Value>)>) -> Result<Vec<Result<T, Aria2Error>>, Aria2Error> {
+    let methods = calls.into_iter().map(|(method, params)| json!({
+        "methodName": method,
+        "params": with_token(params)
+    })).collect::<Vec<_>>();
+    let results: Vec<Value> = request("system.multicall", vec![json!(methods)]).await?;
+    Ok(results.into_iter().map(|result| match result {
+        Value::Array(mut values) if values.len() == 1 => serde_json::from_value(values.remove(0))
+            .map_err(|e| Aria2Error::Parse(e.to_string())),
+        other => match serde_json::from_value::<RpcErrorObject>(other) {
+            Ok(e) => Err(Aria2Error::Rpc { code: e.code, message: e.message }),
+            Err(e) => Err(Aria2Error::Parse(e.to_string())),
+        }
+    }).collect())
+}
+
+pub async fn add_uri(uris: &[String], options: Map<String, Value>) -> Result<String, Aria2Error> {
+    call("aria2.addUri", vec![json!(uris), Value::Object(options)]).await
+}
+
+pub async fn tell_status(gid: &str) -> Result<Aria2Status, Aria2Error> {
+    call("aria2.tellStatus", vec![json!(gid)]).await
+}
+
+pub async fn tell_status_many(gids: &[String]) -> Result<Vec<Result<Aria2Status, Aria2Error>>, Aria2Error> {
+    multicall(gids.iter().map(|gid| ("aria2.tellStatus", vec![json!(gid)])).collect()).await
+}
+
+pub async fn tell_active() -> Result<Vec<Aria2Status>, Aria2Error> {
+    call("aria2.tellActive", vec![]).await
+}
+
+pub async fn tell_waiting(offset: usize, num: usize) -> Result<Vec<Aria2Status>, Aria2Error> {
+    call("aria2.tellWaiting", vec![json!(offset), json!(num)]).await
+}
+
+pub async fn pause(gid: &str) -> Result<String, Aria2Error> {
+    call("aria2.pause", vec![json!(gid)]).await
+}
+
+pub async fn unpause(gid: &str) -> Result<String, Aria2Error> {
+    call("aria2.unpause", vec![json!(gid)]).await
+}
+
+pub async fn remove(gid: &str) -> Result<String, Aria2Error> {
+    call("aria2.remove", vec![json!(gid)]).await
+}
+
+pub async fn remove_download_result(gid: &str) -> Result<String, Aria2Error> {
+    call("aria2.removeDownloadResult", vec![json!(gid)]).await
+}
+
+pub async fn change_uri(gid: &str, del_uris: &[String], add_uris: &[String]) -> Result<Vec<u64>, Aria2Error> {
+    call("aria2.changeUri", vec![json!(gid), json!(1), json!(del_uris), json!(add_uris)]).await
+}
+
+pub async fn get_files(gid: &str) -> Result<Vec<Aria2File>, Aria2Error> {
+    call("aria2.getFiles", vec![json!(gid)]).await
+}
+
+pub async fn get_global_stat() -> Result<Aria2GlobalStat, Aria2Error> {
+    call("aria2.getGlobalStat", vec![]).await
+}
+
+pub async fn change_global_option(options: Map<String, Value>) -> Result<String, Aria2Error> {
+    call("aria2.changeGlobalOption", vec![Value::Object(options)]).await
+}
+
+pub async fn change_option(gid: &str, options: Map<String, Value>) -> Result<String, Aria2Error> {
+    call("aria2.changeOption", vec![json!(gid), Value::Object(options)]).await
+}
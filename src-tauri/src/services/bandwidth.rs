@@ -0,0 +1,147 @@
+use chrono::{Local, NaiveTime};
+use lazy_static::lazy_static;
+use serde_json::{Map, Value};
+use std::sync::{atomic::{AtomicBool, Ordering}, RwLock};
+use tokio::time::{interval, Duration};
+use crate::{aria2c, aria2rpc, SettingsSchedule, CONFIG};
+
+lazy_static! {
+    static ref WINDOW_OPEN: AtomicBool = AtomicBool::new(true);
+    static ref LIMITS: RwLock<(u64, u64)> = RwLock::new((0, 0));
+}
+
+const MODES: [&str; 3] = ["off", "window", "throttle"];
+
+fn parse_time(time: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M").ok()
+}
+
+pub fn validate(schedule: &SettingsSchedule) -> Result<(), String> {
+    if !MODES.contains(&schedule.mode.as_str()) {
+        return Err(format!("Unknown schedule mode {}, expected one of {}", schedule.mode, MODES.join(", ")));
+    }
+    for time in [&schedule.start, &schedule.end] {
+        parse_time(time).ok_or(format!("Invalid schedule time {}, expected HH:MM", time))?;
+    }
+    Ok(())
+}
+
+// A window that ends where it starts spans the whole day. Times rw_config
+// would reject can only come from older settings, those never close it.
+fn in_window(schedule: &SettingsSchedule, now: NaiveTime) -> bool {
+    let (Some(start), Some(end)) = (parse_time(&schedule.start), parse_time(&schedule.end)) else {
+        return true;
+    };
+    if start == end {
+        true
+    } else if start < end {
+        now >= start && now < end
+    } else {
+        now >= start || now < end
+    }
+}
+
+fn format_limit(kib: u64) -> Value {
+    if kib == 0 { "0".into() } else { format!("{}K", kib).into() }
+}
+
+// Whether the scheduler may run downloads right now. Only the "window"
+// mode ever closes it, "throttle" mode just lowers the speed limit.
+pub fn window_open() -> bool {
+    WINDOW_OPEN.load(Ordering::SeqCst)
+}
+
+// Overall and per-task limits in KiB/s, 0 meaning unlimited. aria2 gets
+// them over RPC, the native downloader reads them from here.
+pub fn limits() -> (u64, u64) {
+    *LIMITS.read().unwrap()
+}
+
+fn current_limits() -> (bool, u64, u64) {
+    let config = CONFIG.read().unwrap();
+    let schedule = &config.schedule;
+    let inside = in_window(schedule, Local::now().time());
+    match schedule.mode.as_str() {
+        "throttle" if !inside => (true, schedule.limit, config.task_down_limit),
+        "window" => (inside, config.down_limit, config.task_down_limit),
+        _ => (true, config.down_limit, config.task_down_limit)
+    }
+}
+
+pub async fn apply() -> Result<(), String> {
+    let (open, global_limit, task_limit) = current_limits();
+    *LIMITS.write().unwrap() = (global_limit, task_limit);
+    // The window holds tasks on every backend, aria2 running or not
+    if WINDOW_OPEN.swap(open, Ordering::SeqCst) != open {
+        log::info!("Download window {}", if open { "opened" } else { "closed" });
+        aria2c::hold_doing(!open).await;
+        aria2c::reschedule();
+    }
+    if !aria2c::alive() { return Ok(()); }
+    let mut options = Map::new();
+    options.insert("max-overall-download-limit".into(), format_limit(global_limit));
+    options.insert("max-download-limit".into(), format_limit(task_limit));
+    aria2rpc::change_global_option(options).await.map_err(|e| e.to_string())?;
+    let mut task_options = Map::new();
+    task_options.insert("max-download-limit".into(), format_limit(task_limit));
+    for gid in aria2c::active_aria2_gids().await {
+        if let Err(e) = aria2rpc::change_option(&gid, task_options.clone()).await {
+            log::warn!("Failed to apply speed limit to {}: {}", gid, e);
+        }
+    }
+    Ok(())
+}
+
+pub async fn watch() {
+    let mut ticker = interval(Duration::from_secs(30));
+    let mut last = None;
+    loop {
+        ticker.tick().await;
+        let limits = current_limits();
+        if last == Some(limits) { continue; }
+        match apply().await {
+            // Keep trying until aria2 is up to take the limits as well
+            Ok(_) if aria2c::alive() => last = Some(limits),
+            Ok(_) => (),
+            Err(e) => log::warn!("Failed to apply bandwidth schedule: {}", e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_window_covers_same_day_and_overnight_windows() {
+        // start, end, now, expected
+        let cases = [
+            ("09:00", "17:00", "09:00", true),
+            ("09:00", "17:00", "12:30", true),
+            ("09:00", "17:00", "17:00", false),
+            ("09:00", "17:00", "08:59", false),
+            ("23:00", "07:00", "23:30", true),
+            ("23:00", "07:00", "03:00", true),
+            ("23:00", "07:00", "07:00", false),
+            ("23:00", "07:00", "12:00", false),
+            ("01:00", "01:00", "01:00", true),
+            ("01:00", "01:00", "00:59", true),
+            ("01:00", "01:00", "13:00", true),
+        ];
+        for (start, end, now, expected) in cases {
+            let schedule = SettingsSchedule { mode: "window".into(), start: start.into(), end: end.into(), limit: 0 };
+            let time = NaiveTime::parse_from_str(now, "%H:%M").unwrap();
+            assert_eq!(in_window(&schedule, time), expected, "{}-{} at {}", start, end, now);
+        }
+    }
+
+    #[test]
+    fn validate_rejects_unknown_modes_and_malformed_times() {
+        let schedule = SettingsSchedule { mode: "window".into(), start: "23:00".into(), end: "07:30".into(), limit: 0 };
+        assert!(validate(&schedule).is_ok());
+        assert!(validate(&SettingsSchedule { mode: "nightly".into(), ..schedule.clone() }).is_err());
+        assert!(validate(&SettingsSchedule { start: "25:00".into(), ..schedule.clone() }).is_err());
+        assert!(validate(&SettingsSchedule { end: "7pm".into(), ..schedule.clone() }).is_err());
+        assert!(validate(&SettingsSchedule { start: String::new(), ..schedule }).is_err());
+    }
+}
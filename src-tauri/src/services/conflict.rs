@@ -0,0 +1,69 @@
+use std::path::{Path, PathBuf};
+use crate::{aria2c::{QueueInfo, DOING_QUEUE, WAITING_QUEUE}, downloads, CONFIG};
+
+pub enum Resolution {
+    Write(PathBuf),
+    Skip(String),
+}
+
+async fn queued(path: &Path) -> bool {
+    let waiting_queue = WAITING_QUEUE.lock().await;
+    let doing_queue = DOING_QUEUE.lock().await;
+    waiting_queue.iter().chain(doing_queue.iter()).any(|info| info.output_path == path)
+}
+
+async fn taken(path: &Path) -> bool {
+    path.exists() || queued(path).await
+}
+
+// "name.mp4" -> "name (1).mp4", "name (2).mp4", ...
+pub async fn unique_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let ext = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    let mut index = 1;
+    loop {
+        let candidate = path.with_file_name(format!("{} ({}){}", stem, index, ext));
+        if !taken(&candidate).await { return candidate; }
+        index += 1;
+    }
+}
+
+// Worst to best. The ids themselves don't sort that way, 30280 (192K) is
+// above both 30250 (Dolby) and 30251 (Hi-Res), see mediaMap in the store.
+const VIDEO_RANKS: [i32; 12] = [6, 16, 32, 64, 74, 80, 112, 116, 120, 125, 126, 127];
+const AUDIO_RANKS: [i32; 5] = [30216, 30232, 30280, 30250, 30251];
+
+// Unknown ids and missing streams rank lowest
+fn position(ranks: &[i32], id: i32) -> usize {
+    ranks.iter().position(|&rank| rank == id).map_or(0, |index| index + 1)
+}
+
+// Video first, audio second
+fn rank(info: &QueueInfo) -> (usize, usize) {
+    info.quality.as_ref().map_or((0, 0), |q| {
+        (if info.urls.video.is_empty() { 0 } else { position(&VIDEO_RANKS, q.dms) },
+         if info.urls.audio.is_empty() { 0 } else { position(&AUDIO_RANKS, q.ads) })
+    })
+}
+
+// Decided when a task is queued, so nothing gets downloaded just to be thrown away
+pub async fn resolve(path: PathBuf, info: &QueueInfo) -> Result<Resolution, String> {
+    if !taken(&path).await { return Ok(Resolution::Write(path)); }
+    let policy = CONFIG.read().unwrap().on_conflict.clone();
+    Ok(match policy.as_str() {
+        "overwrite" => Resolution::Write(path),
+        "rename" => Resolution::Write(unique_path(&path).await),
+        "keep_higher" => {
+            if queued(&path).await {
+                return Ok(Resolution::Skip(format!("{} is already queued", path.display())));
+            }
+            let existing = downloads::find_by_output(&path).await.map_err(|e| e.to_string())?;
+            match existing {
+                Some(existing) if rank(info) > rank(&existing.value.queue_info) => Resolution::Write(path),
+                Some(_) => Resolution::Skip(format!("{} already exists in the same or a better quality", path.display())),
+                None => Resolution::Skip(format!("{} already exists and its quality is unknown", path.display())),
+            }
+        },
+        _ => Resolution::Skip(format!("{} already exists", path.display())),
+    })
+}
@@ -0,0 +1,175 @@
+use lazy_static::lazy_static;
+use serde_json::json;
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::RwLock};
+use tauri::Emitter;
+use tauri_plugin_http::reqwest::{header::{CONTENT_LENGTH, CONTENT_RANGE, RANGE}, Client, StatusCode};
+use crate::{aria2c::{DownloadTask, VideoInfo}, aria2rpc, finalize, get_window, init_client, CONFIG};
+
+// Keep some headroom so the disk isn't filled to the last byte, ffmpeg's
+// muxing overhead and the .aria2 control files need a little room too.
+const MARGIN: u64 = 256 * 1024 * 1024;
+
+pub const MODES: [&str; 3] = ["off", "warn", "refuse"];
+
+#[derive(Clone, Debug)]
+struct Reservation {
+    fs: String,
+    dir: PathBuf,
+    bytes: u64,
+    // Where those bytes end up. Whatever they hold already lowers the free
+    // space, so it no longer counts against the reservation.
+    files: Vec<PathBuf>,
+}
+
+impl Reservation {
+    fn outstanding(&self) -> u64 {
+        self.bytes.saturating_sub(self.files.iter().map(|f| allocated(f)).sum())
+    }
+}
+
+lazy_static! {
+    // Task id -> bytes it expects to write, per filesystem
+    static ref RESERVATIONS: RwLock<HashMap<String, Vec<Reservation>>> = RwLock::new(HashMap::new());
+}
+
+// Directories may not exist yet, measure the closest ancestor that does.
+fn existing_ancestor(path: &Path) -> PathBuf {
+    path.ancestors().find(|p| p.exists()).unwrap_or(path).to_path_buf()
+}
+
+#[cfg(unix)]
+fn fs_key(path: &Path) -> String {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(existing_ancestor(path))
+        .map(|m| m.dev().to_string())
+        .unwrap_or_else(|_| path.to_string_lossy().into())
+}
+
+#[cfg(windows)]
+fn fs_key(path: &Path) -> String {
+    path.components().next()
+        .map(|c| c.as_os_str().to_string_lossy().to_uppercase())
+        .unwrap_or_default()
+}
+
+// What a file takes up on disk. Both downloaders may create it sparse at its
+// full length, which says nothing about the space it uses yet.
+#[cfg(unix)]
+fn allocated(path: &Path) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).map(|m| m.blocks() * 512).unwrap_or(0)
+}
+
+#[cfg(windows)]
+fn allocated(path: &Path) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+fn available(dir: &Path) -> Result<u64, String> {
+    fs2::available_space(existing_ancestor(dir)).map_err(|e| e.to_string())
+}
+
+fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", size, units[unit])
+}
+
+async fn remote_size(client: &Client, url: &str) -> Option<u64> {
+    // Don't trust content_length() here, it reports the (empty) body of a HEAD
+    let size = match client.head(url).send().await {
+        Ok(resp) if resp.status().is_success() => resp.headers().get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok()),
+        _ => None
+    };
+    if let Some(size) = size.filter(|s| *s > 0) { return Some(size); }
+    // Some servers refuse HEAD, the first byte of a ranged GET still carries the total
+    let resp = client.get(url).header(RANGE, "bytes=0-0").send().await.ok()?;
+    if resp.status() != StatusCode::PARTIAL_CONTENT { return None; }
+    resp.headers().get(CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit('/').next())
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|s| *s > 0)
+}
+
+async fn stream_size(task: &DownloadTask) -> Option<u64> {
+    if let Ok(client) = init_client().await {
+        for url in &task.url {
+            if let Some(size) = remote_size(&client, url).await { return Some(size); }
+        }
+    }
+    // aria2 only learns the length once it has connected, so this helps
+    // with restored tasks but not with ones that were just added paused
+    if task.backend == "native" { return None; }
+    let size: u64 = aria2rpc::get_files(&task.gid).await.ok()?.iter().map(|f| f.length).sum();
+    (size > 0).then_some(size)
+}
+
+fn reserved_by_others(id: &str, fs: &str) -> u64 {
+    RESERVATIONS.read().unwrap().iter()
+        .filter(|(task_id, _)| task_id.as_str() != id)
+        .flat_map(|(_, reservations)| reservations.iter())
+        .filter(|r| r.fs == fs)
+        .map(Reservation::outstanding).sum()
+}
+
+// Works out how much every stream of the task still has to write to the temp
+// directory, plus the merged or moved output in the download directory, then
+// checks it against what's left on each filesystem once other queued tasks
+// are accounted for. With `enforce` a shortage is an error, otherwise it's
+// only reported to the frontend.
+pub async fn reserve(info: &VideoInfo, enforce: bool) -> Result<(), String> {
+    let mode = CONFIG.read().unwrap().disk_check.clone();
+    if mode == "off" { return Ok(()); }
+    let mut total = 0;
+    let mut needs: HashMap<String, Reservation> = HashMap::new();
+    for task in &info.tasks {
+        let Some(size) = stream_size(task).await else {
+            log::warn!("Unknown size of {}, skipping its space check", task.path.display());
+            continue;
+        };
+        total += size;
+        let dir = task.path.parent().unwrap_or(&task.path).to_path_buf();
+        let fs = fs_key(&dir);
+        let need = needs.entry(fs.clone()).or_insert(Reservation { fs, dir, bytes: 0, files: vec![] });
+        need.bytes += size;
+        need.files.push(task.path.clone());
+    }
+    // A plain rename within the same filesystem needs no extra room, a merge
+    // or a move across filesystems writes a full second copy.
+    let out_fs = fs_key(&info.output_path);
+    if info.action == "media" || !needs.contains_key(&out_fs) {
+        let need = needs.entry(out_fs.clone())
+            .or_insert(Reservation { fs: out_fs, dir: info.output_path.clone(), bytes: 0, files: vec![] });
+        need.bytes += total;
+        need.files.push(finalize::part_path(&info.output_path));
+    }
+    let mut shortages = vec![];
+    for need in needs.values() {
+        let free = available(&need.dir)?.saturating_sub(reserved_by_others(&info.id, &need.fs));
+        let outstanding = need.outstanding();
+        if outstanding + MARGIN > free {
+            shortages.push(format!("{} needs {} but only {} is left on {}",
+                info.display_name, format_size(outstanding), format_size(free), need.dir.display()));
+        }
+    }
+    if !shortages.is_empty() {
+        let message = format!("Not enough disk space: {}", shortages.join("; "));
+        if enforce && mode == "refuse" { return Err(message); }
+        log::warn!("{}", message);
+        get_window().emit("disk-space", json!({ "id": info.id, "message": message })).unwrap();
+    }
+    RESERVATIONS.write().unwrap().insert(info.id.clone(), needs.into_values().collect());
+    Ok(())
+}
+
+pub fn release(id: &str) {
+    RESERVATIONS.write().unwrap().remove(id);
+}
@@ -0,0 +1,102 @@
+use async_trait::async_trait;
+use serde_json::Map;
+use std::path::Path;
+use tokio::{sync::broadcast, time::{sleep, Duration}};
+use crate::{aria2c, aria2rpc::{self, Aria2Event, Aria2State, Aria2Status}, native::NativeDownloader};
+
+// Statuses and events reuse aria2's shapes, so the queue logic in aria2c.rs
+// doesn't need to know which backend a task runs on.
+#[async_trait]
+pub trait Downloader: Send + Sync {
+    async fn ready(&self) -> Result<(), String>;
+    async fn add(&self, urls: &[String], path: &Path) -> Result<String, String>;
+    async fn restore(&self, gid: &str, urls: &[String], path: &Path) -> Result<String, String>;
+    async fn start(&self, gid: &str) -> Result<(), String>;
+    async fn pause(&self, gid: &str) -> Result<(), String>;
+    async fn remove(&self, gid: &str) -> Result<(), String>;
+    async fn status(&self, gid: &str) -> Result<Aria2Status, String>;
+    async fn status_many(&self, gids: &[String]) -> Result<Vec<Result<Aria2Status, String>>, String>;
+    async fn change_uris(&self, gid: &str, old: &[String], new: &[String]) -> Result<(), String>;
+    fn subscribe(&self) -> broadcast::Receiver<Aria2Event>;
+}
+
+pub struct Aria2Downloader;
+
+static ARIA2: Aria2Downloader = Aria2Downloader;
+static NATIVE: NativeDownloader = NativeDownloader;
+
+pub fn downloader(backend: &str) -> &'static dyn Downloader {
+    match backend {
+        "native" => &NATIVE,
+        _ => &ARIA2
+    }
+}
+
+#[async_trait]
+impl Downloader for Aria2Downloader {
+    async fn ready(&self) -> Result<(), String> {
+        let mut retries = 0;
+        while let Err(e) = aria2rpc::get_global_stat().await {
+            retries += 1;
+            if retries >= 20 { return Err(e.to_string()); }
+            sleep(Duration::from_millis(250)).await;
+        }
+        Ok(())
+    }
+
+    async fn add(&self, urls: &[String], path: &Path) -> Result<String, String> {
+        let mut options = Map::new();
+        options.insert("dir".into(), path.parent().unwrap().to_string_lossy().into());
+        options.insert("out".into(), path.file_name().unwrap().to_string_lossy().into());
+        options.extend(aria2c::task_proxy_options(urls)?);
+        let gid = aria2rpc::add_uri(urls, options).await.map_err(|e| e.to_string())?;
+        aria2rpc::pause(&gid).await.map_err(|e| e.to_string())?;
+        Ok(gid)
+    }
+
+    async fn restore(&self, gid: &str, urls: &[String], path: &Path) -> Result<String, String> {
+        match aria2rpc::tell_status(gid).await {
+            Ok(status) => {
+                if matches!(status.status, Aria2State::Active | Aria2State::Waiting) {
+                    aria2rpc::pause(gid).await.map_err(|e| e.to_string())?;
+                }
+                Ok(gid.to_string())
+            },
+            Err(_) => {
+                log::info!("{} is unknown to aria2, re-adding {}", gid, path.display());
+                self.add(urls, path).await
+            }
+        }
+    }
+
+    async fn start(&self, gid: &str) -> Result<(), String> {
+        aria2rpc::unpause(gid).await.map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    async fn pause(&self, gid: &str) -> Result<(), String> {
+        aria2rpc::pause(gid).await.map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    async fn remove(&self, gid: &str) -> Result<(), String> {
+        // Stopped downloads can't be removed, only their results can
+        if aria2rpc::remove(gid).await.is_ok() { return Ok(()); }
+        aria2rpc::remove_download_result(gid).await.map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    async fn status(&self, gid: &str) -> Result<Aria2Status, String> {
+        aria2rpc::tell_status(gid).await.map_err(|e| e.to_string())
+    }
+
+    async fn status_many(&self, gids: &[String]) -> Result<Vec<Result<Aria2Status, String>>, String> {
+        Ok(aria2rpc::tell_status_many(gids).await.map_err(|e| e.to_string())?
+            .into_iter().map(|r| r.map_err(|e| e.to_string())).collect())
+    }
+
+    async fn change_uris(&self, gid: &str, old: &[String], new: &[String]) -> Result<(), String> {
+        aria2rpc::change_uri(gid, old, new).await.map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Aria2Event> {
+        aria2rpc::subscribe()
+    }
+}
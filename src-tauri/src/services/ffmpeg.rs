@@ -1,20 +1,45 @@
 use lazy_static::lazy_static;
-use tauri_plugin_shell::{process::CommandChild, ShellExt};
-use std::{collections::{HashSet, VecDeque}, fs, path::PathBuf, sync::{Arc, RwLock}, time::Instant};
+use tauri_plugin_shell::{process::{CommandChild, CommandEvent}, ShellExt};
+use std::{collections::{HashMap, HashSet, VecDeque}, fs, path::{Path, PathBuf}, sync::{Arc, RwLock}, time::Instant};
 use serde_json::{json, Value};
 use tauri::{async_runtime, Emitter, WebviewWindow};
-use tokio::{fs::File, io::{AsyncBufReadExt, AsyncSeekExt, BufReader, SeekFrom}, select, time::{sleep, Duration}};
+use tokio::{fs::File, io::{AsyncBufReadExt, AsyncSeekExt, BufReader, SeekFrom}, time::{sleep, Duration}};
 
-use crate::{aria2c::*, get_app_handle, handle_err, CURRENT_BIN};
+use crate::{aria2c::*, finalize, get_app_handle, handle_err, init_client, CURRENT_BIN};
+
+pub struct Container {
+    pub ext: &'static str,
+    muxer: &'static str,
+    video: &'static [&'static str],
+    audio: &'static [&'static str],
+}
+
+// What each container carries without re-encoding. Players still choke on
+// FLAC inside MP4, so lossless audio goes to MKV.
+const CONTAINERS: [Container; 3] = [
+    Container { ext: "mp4", muxer: "mp4", video: &["AVC", "HEVC", "AV1"], audio: &["AAC", "E-AC-3"] },
+    Container { ext: "mkv", muxer: "matroska", video: &["AVC", "HEVC", "AV1"], audio: &["AAC", "E-AC-3", "FLAC"] },
+    Container { ext: "mov", muxer: "mov", video: &["AVC", "HEVC"], audio: &["AAC"] },
+];
+
+pub const AUDIO_MODES: [&str; 2] = ["copy", "transcode"];
+
+pub struct MergePlan {
+    container: &'static Container,
+    audio_copy: bool,
+    hvc1: bool,
+}
 
 lazy_static! {
-    static ref FFMPEG_CHILD: Arc<RwLock<Option<CommandChild>>> = Arc::new(RwLock::new(None));
+    static ref FFMPEG_CHILDREN: Arc<RwLock<HashMap<String, CommandChild>>> = Arc::new(RwLock::new(HashMap::new()));
 }
 
 pub async fn init_merge(window: &WebviewWindow, info: &VideoInfo) -> Result<(), String> {
     log::info!("Starting merge process for audio");
 
-    let output_path = info.output_path.to_string_lossy().into_owned();
+    // The muxer can't be guessed from a .part name, so it's given explicitly
+    let part_path = finalize::part_path(&info.output_path);
+    let output_path = part_path.to_string_lossy().into_owned();
     let video_filename = info.output_path.file_name()
         .ok_or("Failed to extract video filename")?
         .to_string_lossy().into_owned();
@@ -22,36 +47,227 @@ pub async fn init_merge(window: &WebviewWindow, info: &VideoInfo) -> Result<(),
 
     let video_path = info.video_path.clone();
     let audio_path = info.audio_path.clone();
-    let progress_path = Arc::new(CURRENT_BIN.join("ffmpeg")
-        .join(format!("{}.log", video_filename)));
-
-    let ffmpeg_task = async_runtime::spawn({
-        let progress_path = Arc::clone(&progress_path);
-        async move {
-        let status = app.shell().sidecar("./bin/ffmpeg").unwrap()
-            .args([
-                "-i", video_path.to_str().unwrap(),
-                "-i", audio_path.to_str().unwrap(),
-                "-stats_period", "0.1",
-                "-c:v", "copy",
-                "-c:a", "aac",
-                &output_path, "-progress",
-                progress_path.to_str().unwrap(), "-y"
-            ])
-            .status().await
-            .map_err(|e| handle_err(e.to_string()))?;
-
-        if status.success() {
-            Ok(())
+    let progress_path = CURRENT_BIN.join("ffmpeg")
+        .join(format!("{}.log", video_filename));
+    let plan = plan(&info.queue_info)?;
+    let muxer = plan.container.muxer;
+    let cover_path = download_cover(info).await;
+
+    // Options before an -i belong to that input, so every input goes first
+    let mut args: Vec<String> = vec![
+        "-i".into(), video_path.to_string_lossy().into(),
+        "-i".into(), audio_path.to_string_lossy().into(),
+    ];
+    let mut maps: Vec<String> = ["-map", "0:v:0", "-map", "1:a:0"].map(String::from).into();
+    if let Some(cover) = &cover_path {
+        let (input, output) = cover_args(cover, muxer);
+        args.extend(input);
+        maps.extend(output);
+    }
+    args.extend(maps);
+    args.extend([
+        "-stats_period", "0.1",
+        "-c:v", "copy",
+        "-c:a", if plan.audio_copy { "copy" } else { "aac" },
+    ].map(String::from));
+    // Apple players only take HEVC tagged as hvc1
+    if plan.hvc1 {
+        args.extend(["-tag:v:0", "hvc1"].map(String::from));
+    }
+    args.extend(metadata_args(&info.queue_info));
+    args.extend([
+        "-f".into(), muxer.into(),
+        output_path, "-progress".into(),
+        progress_path.to_string_lossy().into(), "-y".into()
+    ]);
+
+    let spawned = {
+        let mut children = FFMPEG_CHILDREN.write().unwrap();
+        // Checked under the lock cancel takes, so a cancel either finds the
+        // child to kill or keeps it from starting at all
+        if is_cancelled(&info.id) {
+            Err(format!("{} was cancelled", info.display_name))
         } else {
-            Err(format!("FFmpeg exited with status: {}", status.code().unwrap_or(-1)))
+            app.shell().sidecar("./bin/ffmpeg").unwrap()
+                .args(args)
+                .spawn().map_err(|e| handle_err(e.to_string()))
+                .map(|(rx, child)| { children.insert(info.id.clone(), child); rx })
+        }
+    };
+    let mut rx = match spawned {
+        Ok(rx) => rx,
+        Err(e) => {
+            if let Some(cover) = &cover_path {
+                let _ = fs::remove_file(cover);
+            }
+            return Err(e);
+        }
+    };
+
+    let monitor = async_runtime::spawn(
+        monitor_progress(window.clone(), info.clone(), progress_path.clone())
+    );
+    let mut code = None;
+    while let Some(event) = rx.recv().await {
+        if let CommandEvent::Terminated(payload) = event {
+            code = payload.code;
+            break;
+        }
+    }
+    monitor.abort();
+    FFMPEG_CHILDREN.write().unwrap().remove(&info.id);
+    let _ = fs::remove_file(&progress_path);
+    if let Some(cover) = &cover_path {
+        let _ = fs::remove_file(cover);
+    }
+    match code {
+        Some(0) => finalize::promote(&part_path, &info.output_path),
+        code => {
+            let _ = fs::remove_file(&part_path);
+            Err(format!("FFmpeg exited with status: {}", code.unwrap_or(-1)))
         }
-    }});
-    monitor_progress(window, info, &Arc::clone(&progress_path)).await?;
-    select! { _ = ffmpeg_task => Ok(()) }
+    }
+}
+
+// Where the file came from, written as the comment tag
+fn source_url(info: &QueueInfo) -> String {
+    match info.media_type.as_str() {
+        "bangumi" => format!("https://www.bilibili.com/bangumi/play/ep{}", info.eid),
+        "lesson" => format!("https://www.bilibili.com/cheese/play/ep{}", info.eid),
+        "music" => format!("https://www.bilibili.com/audio/au{}", info.id),
+        _ => format!("https://www.bilibili.com/video/av{}", info.id)
+    }
+}
+
+// Plain tags for most players, plus the iTunes TV ones (show, episode_sort,
+// synopsis) that Jellyfin and Plex read from MP4 files.
+fn metadata_args(info: &QueueInfo) -> Vec<String> {
+    let description = info.desc.replace("<br>", "\n");
+    let episode = if info.index > 0 { info.index.to_string() } else { String::new() };
+    [
+        ("title", info.title.clone()),
+        ("artist", info.upper.clone()),
+        ("album_artist", info.upper.clone()),
+        ("album", info.ss_title.clone()),
+        ("show", info.ss_title.clone()),
+        ("track", episode.clone()),
+        ("episode_sort", episode),
+        ("description", description.clone()),
+        ("synopsis", description),
+        ("date", info.pubdate.clone()),
+        ("comment", source_url(info)),
+    ].into_iter()
+        .filter(|(_, value)| !value.trim().is_empty())
+        .flat_map(|(key, value)| ["-metadata".to_string(), format!("{}={}", key, value.trim())])
+        .collect()
+}
+
+// Goes next to the downloaded streams, so it's cleaned up along with them.
+// A missing cover isn't worth failing the merge over.
+async fn download_cover(info: &VideoInfo) -> Option<PathBuf> {
+    let url = &info.queue_info.cover;
+    if url.is_empty() { return None; }
+    let result = async {
+        let client = init_client().await?;
+        let bytes = client.get(url).send().await
+            .and_then(|resp| resp.error_for_status()).map_err(|e| e.to_string())?
+            .bytes().await.map_err(|e| e.to_string())?;
+        // MP4 only takes JPEG and PNG artwork
+        let ext = if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) { "jpg" }
+            else if bytes.starts_with(b"\x89PNG") { "png" }
+            else { return Err("not a JPEG or PNG image".to_string()) };
+        let path = info.video_path.with_file_name(format!("cover.{}", ext));
+        fs::write(&path, &bytes).map_err(|e| e.to_string())?;
+        Ok(path)
+    }.await;
+    result.map_err(|e| log::warn!("Failed to download cover of {}: {}", info.display_name, e)).ok()
+}
+
+// Matroska keeps artwork as an attachment, MP4 as a picture stream. MOV is
+// left without, an extra picture stream would show up as a second video track.
+// Returns the input arguments and the output arguments separately.
+fn cover_args(cover: &Path, muxer: &str) -> (Vec<String>, Vec<String>) {
+    let ext = cover.extension().unwrap_or_default().to_string_lossy().into_owned();
+    let mimetype = if ext == "png" { "image/png" } else { "image/jpeg" };
+    match muxer {
+        "matroska" => (vec![], vec![
+            "-attach".into(), cover.to_string_lossy().into(),
+            "-metadata:s:t:0".into(), format!("mimetype={}", mimetype),
+            "-metadata:s:t:0".into(), format!("filename=cover.{}", ext),
+        ]),
+        "mp4" => (vec![
+            "-i".into(), cover.to_string_lossy().into(),
+        ], vec![
+            "-map".into(), "2:v:0".into(),
+            "-disposition:v:1".into(), "attached_pic".into(),
+        ]),
+        _ => (vec![], vec![])
+    }
+}
+
+// An empty name means the task predates the setting
+pub fn container(name: &str) -> Result<&'static Container, String> {
+    if name.is_empty() { return Ok(&CONTAINERS[0]); }
+    CONTAINERS.iter().find(|c| c.ext == name).ok_or_else(|| format!(
+        "Unknown container {}, expected one of {}",
+        name, CONTAINERS.iter().map(|c| c.ext).collect::<Vec<_>>().join(", ")
+    ))
+}
+
+fn video_codec(cdc: i32) -> Option<&'static str> {
+    match cdc {
+        7 => Some("AVC"),
+        12 => Some("HEVC"),
+        13 => Some("AV1"),
+        _ => None
+    }
+}
+
+fn audio_codec(ads: i32) -> Option<&'static str> {
+    match ads {
+        30216 | 30232 | 30280 => Some("AAC"),
+        30250 => Some("E-AC-3"),
+        30251 => Some("FLAC"),
+        _ => None
+    }
+}
+
+fn holders(codec: &str, video: bool) -> String {
+    CONTAINERS.iter()
+        .filter(|c| if video { c.video } else { c.audio }.contains(&codec))
+        .map(|c| c.ext.to_uppercase())
+        .collect::<Vec<_>>().join(" or ")
+}
+
+// Streams are copied whenever the container can hold them. Video is never
+// re-encoded, audio falls back to AAC only in transcode mode, so anything
+// that would need more than that is refused before downloading.
+pub fn plan(info: &QueueInfo) -> Result<MergePlan, String> {
+    let container = container(&info.container)?;
+    let name = container.ext.to_uppercase();
+    let quality = info.quality.clone().unwrap_or_default();
+    let video = video_codec(quality.cdc);
+    if let Some(video) = video.filter(|v| !container.video.contains(v)) {
+        return Err(format!("{} video can't be stored in {}, pick {} instead", video, name, holders(video, true)));
+    }
+    let audio_copy = match audio_codec(quality.ads) {
+        Some(audio) if container.audio.contains(&audio) => true,
+        Some(audio) if info.audio_mode == "copy" => return Err(format!(
+            "{} audio can't be copied into {}, pick {} or let the audio be transcoded",
+            audio, name, holders(audio, false)
+        )),
+        Some(_) => false,
+        // Unknown codec, let FFmpeg have a go when asked to copy
+        None => info.audio_mode == "copy"
+    };
+    Ok(MergePlan {
+        container,
+        audio_copy,
+        hvc1: video == Some("HEVC") && container.muxer != "matroska",
+    })
 }
 
-async fn monitor_progress(window: &WebviewWindow, info: &VideoInfo, progress_path: &PathBuf) -> Result<(), String> {
+async fn monitor_progress(window: WebviewWindow, info: VideoInfo, progress_path: PathBuf) -> Result<(), String> {
     while !progress_path.exists() {
         sleep(Duration::from_millis(250)).await;
     }
@@ -119,9 +335,57 @@ async fn monitor_progress(window: &WebviewWindow, info: &VideoInfo, progress_pat
     }
 }
 
+pub fn cancel(id: &str) -> bool {
+    match FFMPEG_CHILDREN.write().unwrap().remove(id) {
+        Some(child) => child.kill().is_ok(),
+        None => false
+    }
+}
+
 pub fn kill() -> Result<(), String> {
-    if let Some(sc) = FFMPEG_CHILD.write().unwrap().take() {
+    for (_, sc) in FFMPEG_CHILDREN.write().unwrap().drain() {
         sc.kill().map_err(|e| e.to_string())?;
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_copies_transcodes_or_refuses() {
+        // container, audio mode, codec id, audio id, (audio_copy, hvc1) or None when refused
+        let cases = [
+            ("mp4", "copy", 7, 30280, Some((true, false))),
+            ("mp4", "transcode", 12, 30250, Some((true, true))),
+            ("mkv", "copy", 12, 30251, Some((true, false))),
+            ("mkv", "copy", 13, 30280, Some((true, false))),
+            ("mp4", "transcode", 7, 30251, Some((false, false))),
+            ("mp4", "copy", 7, 30251, None),
+            ("mov", "copy", 7, 30250, None),
+            ("mov", "transcode", 13, 30280, None),
+            ("avi", "copy", 7, 30280, None),
+        ];
+        for (container, audio_mode, cdc, ads, expected) in cases {
+            let info = QueueInfo {
+                container: container.into(),
+                audio_mode: audio_mode.into(),
+                quality: Some(QueueInfoQuality { dms: 80, ads, cdc }),
+                ..Default::default()
+            };
+            let plan = plan(&info).ok().map(|p| (p.audio_copy, p.hvc1));
+            assert_eq!(plan, expected, "{} {} cdc {} ads {}", container, audio_mode, cdc, ads);
+        }
+    }
+
+    #[test]
+    fn plan_names_the_containers_that_fit() {
+        let info = QueueInfo {
+            container: "mov".into(),
+            quality: Some(QueueInfoQuality { dms: 80, ads: 30280, cdc: 13 }),
+            ..Default::default()
+        };
+        assert!(plan(&info).err().unwrap().contains("MP4 or MKV"));
+    }
+}
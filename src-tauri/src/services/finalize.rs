@@ -0,0 +1,79 @@
+use std::{fs::{self, File, OpenOptions}, path::{Path, PathBuf}};
+
+// "name.mp4" -> "name.mp4.part"
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+fn sync_file(path: &Path) -> Result<(), String> {
+    // Windows only flushes handles opened for writing
+    OpenOptions::new().write(true).open(path)
+        .and_then(|file| file.sync_all())
+        .map_err(|e| format!("Failed to sync {}: {}", path.display(), e))
+}
+
+// Makes the rename itself durable, directories can't be opened on Windows
+// and NTFS journals renames anyway.
+fn sync_dir(path: &Path) {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        if let Err(e) = File::open(dir).and_then(|dir| dir.sync_all()) {
+            log::warn!("Failed to sync {}: {}", dir.display(), e);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+fn copy(src: &Path, part: &Path) -> Result<(), String> {
+    fs::copy(src, part).map_err(|e| format!("Failed to copy {} to {}: {}", src.display(), part.display(), e))?;
+    sync_file(part)
+}
+
+// Gives a finished .part file its real name. The rename stays on one
+// filesystem, so the destination is either missing or complete.
+pub fn promote(part: &Path, dest: &Path) -> Result<(), String> {
+    let size = fs::metadata(part).map_err(|e| format!("Failed to read {}: {}", part.display(), e))?.len();
+    if size == 0 {
+        let _ = fs::remove_file(part);
+        return Err(format!("{} is empty", part.display()));
+    }
+    sync_file(part)?;
+    fs::rename(part, dest).map_err(|e| format!("Failed to rename {} to {}: {}", part.display(), dest.display(), e))?;
+    sync_dir(dest);
+    Ok(())
+}
+
+// Moves a downloaded file to its output path. Renames when it can, copies
+// when the temp dir lives on another filesystem or drive. The source is only
+// deleted once the copy has the right size and its final name.
+pub fn commit(src: &Path, dest: &Path) -> Result<(), String> {
+    let expected = fs::metadata(src).map_err(|e| format!("Failed to read {}: {}", src.display(), e))?.len();
+    let part = part_path(dest);
+    let _ = fs::remove_file(&part);
+    let copied = match fs::rename(src, &part) {
+        Ok(()) => false,
+        Err(e) => {
+            log::info!("Can't rename {} to {} ({}), copying instead", src.display(), part.display(), e);
+            if let Err(e) = copy(src, &part) {
+                let _ = fs::remove_file(&part);
+                return Err(e);
+            }
+            true
+        }
+    };
+    let size = fs::metadata(&part).map(|m| m.len()).unwrap_or(0);
+    if size != expected {
+        if copied { let _ = fs::remove_file(&part); }
+        return Err(format!("{} has {} bytes, expected {}", part.display(), size, expected));
+    }
+    promote(&part, dest)?;
+    if copied {
+        if let Err(e) = fs::remove_file(src) {
+            log::warn!("Failed to remove {}: {}", src.display(), e);
+        }
+    }
+    Ok(())
+}
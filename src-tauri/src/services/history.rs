@@ -0,0 +1,132 @@
+use std::{fs, path::{Path, PathBuf}};
+use chrono::{Local, TimeZone};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use crate::{aria2c::{self, VideoInfo}, downloads::{self, HistoryPage, HistoryQuery}, playurl, handle_err, CONFIG};
+
+const CSV_HEADER: [&str; 14] = [
+    "title", "display_name", "upper", "media_type", "aid", "cid", "eid",
+    "video_quality", "audio_quality", "codec", "output_path", "size", "completed_at", "task_id"
+];
+
+// One exported entry. `value` carries the full QueueInfo, so an imported
+// entry can be re-queued like a local one.
+#[derive(Serialize, Deserialize, Debug)]
+struct HistoryRecord {
+    title: String,
+    display_name: String,
+    upper: String,
+    completed_at: i64,
+    value: VideoInfo,
+}
+
+fn csv_field(value: impl ToString) -> String {
+    let value = value.to_string();
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else { value }
+}
+
+fn to_csv(entries: &[downloads::Model]) -> String {
+    let mut lines = vec![CSV_HEADER.join(",")];
+    for entry in entries {
+        let info = &entry.value.queue_info;
+        let quality = info.quality.clone().unwrap_or_default();
+        let size = fs::metadata(&entry.value.output_path).map(|m| m.len().to_string()).unwrap_or_default();
+        let completed_at = Local.timestamp_opt(entry.completed_at, 0).single()
+            .map(|time| time.to_rfc3339()).unwrap_or_default();
+        lines.push([
+            csv_field(&entry.title), csv_field(&entry.display_name), csv_field(&entry.upper),
+            csv_field(&info.media_type), csv_field(info.id), csv_field(info.cid), csv_field(info.eid),
+            csv_field(quality.dms), csv_field(quality.ads), csv_field(quality.cdc),
+            csv_field(entry.value.output_path.display()), csv_field(size),
+            csv_field(completed_at), csv_field(&entry.task_id)
+        ].join(","));
+    }
+    lines.join("\n") + "\n"
+}
+
+fn remove_output(path: &Path) {
+    if path.is_file() {
+        if let Err(e) = fs::remove_file(path) {
+            log::warn!("Failed to remove {}: {}", path.display(), e);
+        }
+    }
+    // Folders made by the filename template shouldn't stay behind empty, but
+    // the download folder itself and anything outside it are left alone
+    let down_dir = CONFIG.read().unwrap().down_dir.clone();
+    for dir in path.ancestors().skip(1) {
+        if dir == down_dir || !dir.starts_with(&down_dir) { break; }
+        if !fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_none()) { break; }
+        if fs::remove_dir(dir).is_err() { break; }
+    }
+}
+
+#[tauri::command]
+pub async fn search_history(query: HistoryQuery) -> Result<HistoryPage, String> {
+    downloads::search(query).await.map_err(handle_err)
+}
+
+#[tauri::command]
+pub async fn delete_history(ids: Vec<i32>, with_files: bool) -> Result<(), String> {
+    for id in ids {
+        let Some(entry) = downloads::find(id).await.map_err(handle_err)? else {
+            log::warn!("No history entry with id {}", id);
+            continue;
+        };
+        if with_files {
+            remove_output(&entry.value.output_path);
+        }
+        downloads::delete(&entry.task_id).await.map_err(handle_err)?;
+        aria2c::update_queue("remove", Some(VideoInfo { id: entry.task_id, ..Default::default() })).await;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn requeue_history(id: i32) -> Result<Value, String> {
+    let entry = downloads::find(id).await.map_err(handle_err)?
+        .ok_or_else(|| handle_err(format!("No history entry with id {}", id)))?;
+    let mut queue_info = entry.value.queue_info;
+    let expired = queue_info.urls.video.iter().chain(queue_info.urls.audio.iter())
+        .any(|url| playurl::is_expired(url));
+    if expired {
+        queue_info.urls = playurl::resolve(&queue_info).await.map_err(handle_err)?;
+    }
+    aria2c::push_back_queue(queue_info).await
+}
+
+#[tauri::command]
+pub async fn export_history(path: PathBuf, format: String) -> Result<usize, String> {
+    let entries = downloads::all().await.map_err(handle_err)?;
+    let content = match format.as_str() {
+        "json" => serde_json::to_string_pretty(&entries.iter().map(|entry| HistoryRecord {
+            title: entry.title.clone(),
+            display_name: entry.display_name.clone(),
+            upper: entry.upper.clone(),
+            completed_at: entry.completed_at,
+            value: entry.value.clone(),
+        }).collect::<Vec<_>>()).map_err(handle_err)?,
+        "csv" => to_csv(&entries),
+        _ => return Err(handle_err(format!("Unknown export format {}", format)))
+    };
+    fs::write(&path, content).map_err(handle_err)?;
+    log::info!("Exported {} history entries to {}", entries.len(), path.display());
+    Ok(entries.len())
+}
+
+#[tauri::command]
+pub async fn import_history(path: PathBuf) -> Result<usize, String> {
+    let content = fs::read_to_string(&path).map_err(handle_err)?;
+    let records: Vec<HistoryRecord> = serde_json::from_str(&content)
+        .map_err(|e| handle_err(format!("{} is not a history export: {}", path.display(), e)))?;
+    let entries = records.into_iter()
+        .filter(|record| !record.value.id.is_empty())
+        .map(|record| (record.value, record.completed_at))
+        .collect();
+    let added = downloads::merge(entries).await.map_err(handle_err)?;
+    downloads::load().await.map_err(handle_err)?;
+    aria2c::update_queue("restore", None).await;
+    log::info!("Imported {} history entries from {}", added, path.display());
+    Ok(added)
+}
@@ -1,7 +1,18 @@
 pub mod aria2c;
+pub mod aria2rpc;
+pub mod bandwidth;
+pub mod conflict;
+pub mod diskspace;
+pub mod downloader;
 pub mod ffmpeg;
+pub mod finalize;
+pub mod history;
 pub mod storage;
 pub mod login;
+pub mod native;
+pub mod playurl;
+pub mod proxy;
+pub mod template;
 
 use std::{error::Error, fs, path::PathBuf, sync::Arc};
 use lazy_static::lazy_static;
@@ -17,11 +28,17 @@ lazy_static! {
 pub async fn init(app_handle: AppHandle<Wry>) -> Result<(), Box<dyn Error>> {
     APP_HANDLE.set(app_handle).unwrap();
     if !WORKING_DIR.exists() { fs::create_dir_all(WORKING_DIR.as_os_str())? }
-    aria2c::init()?;
     storage::init().await?;
     login::init();
     let secret = SECRET.read().unwrap().clone();
     rw_config("init", None, secret).await?;
+    // Started after the stored settings are loaded, so it gets them on its command line
+    if let Err(e) = aria2c::init() {
+        log::error!("Failed to start aria2c, only the native downloader will work: {}", e);
+    }
+    if let Err(e) = aria2c::restore().await {
+        log::error!("Failed to restore the download queue: {}", e);
+    }
     Ok(())
 }
 
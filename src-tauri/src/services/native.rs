@@ -0,0 +1,370 @@
+use async_trait::async_trait;
+use futures_util::future::try_join_all;
+use lazy_static::lazy_static;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Serialize, Deserialize};
+use std::{collections::HashMap, io::{ErrorKind, SeekFrom}, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex, RwLock}, time::Instant};
+use tauri::async_runtime;
+use tauri_plugin_http::reqwest::{header::{CONTENT_RANGE, RANGE}, Client, StatusCode};
+use tokio::{fs::{self, OpenOptions}, io::{AsyncSeekExt, AsyncWriteExt}, select, sync::{broadcast, watch}, time::{interval, sleep, Duration}};
+use crate::{aria2rpc::{Aria2Event, Aria2EventKind, Aria2State, Aria2Status}, bandwidth, downloader::Downloader, init_client};
+
+const CONNECTIONS: u64 = 4;
+const MIN_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+lazy_static! {
+    static ref TASKS: Arc<RwLock<HashMap<String, Arc<NativeTask>>>> = Arc::new(RwLock::new(HashMap::new()));
+    static ref EVENTS: broadcast::Sender<Aria2Event> = broadcast::channel(256).0;
+    static ref OVERALL_BUDGET: Mutex<Budget> = Mutex::new(Budget::default());
+}
+
+pub struct NativeDownloader;
+
+struct NativeTask {
+    gid: String,
+    urls: RwLock<Vec<String>>,
+    path: PathBuf,
+    state: RwLock<Aria2State>,
+    total: AtomicU64,
+    completed: AtomicU64,
+    speed: AtomicU64,
+    error: RwLock<Option<(i64, String)>>,
+    stop: Mutex<Option<watch::Sender<bool>>>,
+    budget: Mutex<Budget>,
+}
+
+// Bytes taken in the current one-second window of a speed limit
+struct Budget {
+    start: Instant,
+    used: u64,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget { start: Instant::now(), used: 0 }
+    }
+}
+
+// Persisted next to the file as `<name>.native`, so a paused or interrupted
+// download resumes from the bytes already written.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Control {
+    total: u64,
+    chunks: Vec<Chunk>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Chunk {
+    start: u64,
+    end: u64,
+    done: u64,
+}
+
+enum Stop {
+    Stopped,
+    Failed(i64, String),
+}
+
+impl From<std::io::Error> for Stop {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::StorageFull => Stop::Failed(9, e.to_string()),
+            _ => Stop::Failed(17, e.to_string()),
+        }
+    }
+}
+
+impl From<tauri_plugin_http::reqwest::Error> for Stop {
+    fn from(e: tauri_plugin_http::reqwest::Error) -> Self {
+        if e.is_timeout() { Stop::Failed(2, e.to_string()) } else { Stop::Failed(6, e.to_string()) }
+    }
+}
+
+fn control_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.native", path.to_string_lossy()))
+}
+
+// Waits until `bytes` fit within `limit` KiB/s, the same limits aria2 gets
+async fn throttle(budget: &Mutex<Budget>, limit: u64, bytes: u64) {
+    if limit == 0 { return; }
+    let wait = {
+        let mut budget = budget.lock().unwrap();
+        if budget.start.elapsed() >= Duration::from_secs(1) {
+            *budget = Budget::default();
+        }
+        budget.used += bytes;
+        let allowed = limit * 1024;
+        if budget.used <= allowed { return; }
+        Duration::from_secs_f64(budget.used as f64 / allowed as f64).saturating_sub(budget.start.elapsed())
+    };
+    sleep(wait).await;
+}
+
+fn emit(gid: &str, kind: Aria2EventKind) {
+    let _ = EVENTS.send(Aria2Event { gid: gid.to_string(), kind });
+}
+
+fn check_status(status: StatusCode) -> Result<(), Stop> {
+    if status.is_success() { return Ok(()); }
+    Err(Stop::Failed(22, format!("The response status is not successful. status={}", status.as_u16())))
+}
+
+async fn probe(client: &Client, url: &str) -> Result<(u64, bool), Stop> {
+    let resp = client.get(url).header(RANGE, "bytes=0-0").send().await?;
+    check_status(resp.status())?;
+    if resp.status() == StatusCode::PARTIAL_CONTENT {
+        let total = resp.headers().get(CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit('/').next())
+            .and_then(|v| v.parse::<u64>().ok())
+            .ok_or(Stop::Failed(1, "Missing Content-Range in ranged response".into()))?;
+        Ok((total, true))
+    } else {
+        Ok((resp.content_length().unwrap_or(0), false))
+    }
+}
+
+fn plan(total: u64, ranged: bool) -> Control {
+    let count = if ranged { (total / MIN_CHUNK_SIZE).clamp(1, CONNECTIONS) } else { 1 };
+    let size = total.div_ceil(count).max(1);
+    let chunks = (0..count).map(|i| Chunk {
+        start: i * size,
+        end: ((i + 1) * size).min(total).saturating_sub(1),
+        done: 0
+    }).collect();
+    Control { total, chunks }
+}
+
+async fn fetch_chunk(
+    client: Client, url: String, path: PathBuf, index: usize,
+    control: Arc<Mutex<Control>>, task: Arc<NativeTask>, ranged: bool
+) -> Result<(), Stop> {
+    let (start, end, done) = {
+        let control = control.lock().unwrap();
+        let chunk = &control.chunks[index];
+        (chunk.start, chunk.end, chunk.done)
+    };
+    if ranged && start + done > end { return Ok(()); }
+    let mut file = OpenOptions::new().write(true).open(&path).await?;
+    let offset = if ranged { start + done } else { 0 };
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut request = client.get(&url);
+    if ranged {
+        request = request.header(RANGE, format!("bytes={}-{}", offset, end));
+    }
+    let mut resp = request.send().await?;
+    check_status(resp.status())?;
+    // A server ignoring Range sends the whole file, which must not land at
+    // this chunk's offset. The retry probes again, usually on another mirror.
+    if ranged && resp.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(Stop::Failed(22, format!("Expected a partial response for bytes {}-{}. status={}", offset, end, resp.status().as_u16())));
+    }
+    if !ranged {
+        task.completed.fetch_sub(done, Ordering::SeqCst);
+        control.lock().unwrap().chunks[index].done = 0;
+    }
+    while let Some(bytes) = resp.chunk().await? {
+        file.write_all(&bytes).await?;
+        control.lock().unwrap().chunks[index].done += bytes.len() as u64;
+        task.completed.fetch_add(bytes.len() as u64, Ordering::SeqCst);
+        let (overall_limit, task_limit) = bandwidth::limits();
+        throttle(&task.budget, task_limit, bytes.len() as u64).await;
+        throttle(&OVERALL_BUDGET, overall_limit, bytes.len() as u64).await;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+async fn save_control(path: &Path, control: &Arc<Mutex<Control>>) {
+    let control = control.lock().unwrap().clone();
+    if let Ok(json) = serde_json::to_vec(&control) {
+        let _ = fs::write(control_path(path), json).await;
+    }
+}
+
+async fn run(task: Arc<NativeTask>, mut stop: watch::Receiver<bool>) -> Result<(), Stop> {
+    let client = init_client().await.map_err(|e| Stop::Failed(1, e))?;
+    let url = task.urls.read().unwrap().first().cloned()
+        .ok_or(Stop::Failed(1, "No URL to download".into()))?;
+    let (total, ranged) = probe(&client, &url).await?;
+    let control = fs::read(control_path(&task.path)).await.ok()
+        .and_then(|bytes| serde_json::from_slice::<Control>(&bytes).ok())
+        .filter(|control| ranged && control.total == total && task.path.exists())
+        .unwrap_or_else(|| plan(total, ranged));
+    if let Some(parent) = task.path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(&task.path).await?;
+    file.set_len(total).await?;
+    drop(file);
+    task.total.store(total, Ordering::SeqCst);
+    task.completed.store(control.chunks.iter().map(|c| c.done).sum(), Ordering::SeqCst);
+    let control = Arc::new(Mutex::new(control));
+    let chunks = (0..control.lock().unwrap().chunks.len()).map(|index| fetch_chunk(
+        client.clone(), url.clone(), task.path.clone(), index,
+        control.clone(), task.clone(), ranged
+    ));
+    let downloads = try_join_all(chunks);
+    tokio::pin!(downloads);
+    let mut ticker = interval(Duration::from_secs(1));
+    let mut last_completed = task.completed.load(Ordering::SeqCst);
+    let result = loop {
+        select! {
+            result = &mut downloads => break result.map(|_| ()),
+            _ = stop.changed() => break Err(Stop::Stopped),
+            _ = ticker.tick() => {
+                let completed = task.completed.load(Ordering::SeqCst);
+                task.speed.store(completed.saturating_sub(last_completed), Ordering::SeqCst);
+                last_completed = completed;
+                save_control(&task.path, &control).await;
+            }
+        }
+    };
+    task.speed.store(0, Ordering::SeqCst);
+    match result {
+        Ok(_) => { let _ = fs::remove_file(control_path(&task.path)).await; },
+        // Removed while running, nothing left to resume
+        Err(_) if *task.state.read().unwrap() == Aria2State::Removed => {
+            let _ = fs::remove_file(control_path(&task.path)).await;
+            let _ = fs::remove_file(&task.path).await;
+        },
+        Err(_) => save_control(&task.path, &control).await
+    }
+    result
+}
+
+fn get_task(gid: &str) -> Result<Arc<NativeTask>, String> {
+    TASKS.read().unwrap().get(gid).cloned().ok_or(format!("GID {} is not found", gid))
+}
+
+fn stop_worker(task: &NativeTask) {
+    if let Some(stop) = task.stop.lock().unwrap().take() {
+        let _ = stop.send(true);
+    }
+}
+
+#[async_trait]
+impl Downloader for NativeDownloader {
+    async fn ready(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    async fn add(&self, urls: &[String], path: &Path) -> Result<String, String> {
+        let gid: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16).map(char::from)
+            .collect();
+        TASKS.write().unwrap().insert(gid.clone(), Arc::new(NativeTask {
+            gid: gid.clone(),
+            urls: RwLock::new(urls.to_vec()),
+            path: path.to_path_buf(),
+            state: RwLock::new(Aria2State::Paused),
+            total: AtomicU64::new(0),
+            completed: AtomicU64::new(0),
+            speed: AtomicU64::new(0),
+            error: RwLock::new(None),
+            stop: Mutex::new(None),
+            budget: Mutex::new(Budget::default()),
+        }));
+        Ok(gid)
+    }
+
+    async fn restore(&self, gid: &str, urls: &[String], path: &Path) -> Result<String, String> {
+        if TASKS.read().unwrap().contains_key(gid) {
+            return Ok(gid.to_string());
+        }
+        self.add(urls, path).await
+    }
+
+    async fn start(&self, gid: &str) -> Result<(), String> {
+        let task = get_task(gid)?;
+        {
+            let mut state = task.state.write().unwrap();
+            if !matches!(*state, Aria2State::Paused | Aria2State::Waiting) {
+                return Err(format!("GID {} cannot be unpaused now", gid));
+            }
+            *state = Aria2State::Active;
+        }
+        let (tx, rx) = watch::channel(false);
+        *task.stop.lock().unwrap() = Some(tx);
+        *task.error.write().unwrap() = None;
+        emit(gid, Aria2EventKind::Start);
+        async_runtime::spawn(async move {
+            match run(task.clone(), rx).await {
+                Ok(_) => {
+                    *task.state.write().unwrap() = Aria2State::Complete;
+                    emit(&task.gid, Aria2EventKind::Complete);
+                },
+                Err(Stop::Stopped) => {},
+                Err(Stop::Failed(code, message)) => {
+                    *task.state.write().unwrap() = Aria2State::Error;
+                    *task.error.write().unwrap() = Some((code, message));
+                    emit(&task.gid, Aria2EventKind::Error);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn pause(&self, gid: &str) -> Result<(), String> {
+        let task = get_task(gid)?;
+        {
+            let mut state = task.state.write().unwrap();
+            if !matches!(*state, Aria2State::Active | Aria2State::Waiting) {
+                return Err(format!("GID {} cannot be paused now", gid));
+            }
+            *state = Aria2State::Paused;
+        }
+        stop_worker(&task);
+        emit(gid, Aria2EventKind::Pause);
+        Ok(())
+    }
+
+    async fn remove(&self, gid: &str) -> Result<(), String> {
+        let task = get_task(gid)?;
+        let state = *task.state.read().unwrap();
+        if matches!(state, Aria2State::Active | Aria2State::Waiting | Aria2State::Paused) {
+            // Marked first, so the worker cleans up instead of saving its progress
+            *task.state.write().unwrap() = Aria2State::Removed;
+            stop_worker(&task);
+            emit(gid, Aria2EventKind::Stop);
+        } else {
+            TASKS.write().unwrap().remove(gid);
+        }
+        Ok(())
+    }
+
+    async fn status(&self, gid: &str) -> Result<Aria2Status, String> {
+        let task = get_task(gid)?;
+        let error = task.error.read().unwrap().clone();
+        let status = *task.state.read().unwrap();
+        Ok(Aria2Status {
+            gid: task.gid.clone(),
+            status,
+            total_length: task.total.load(Ordering::SeqCst),
+            completed_length: task.completed.load(Ordering::SeqCst),
+            download_speed: task.speed.load(Ordering::SeqCst),
+            error_code: error.as_ref().map(|e| e.0),
+            error_message: error.map(|e| e.1),
+            dir: task.path.parent().map(|p| p.to_string_lossy().into_owned()),
+            files: vec![]
+        })
+    }
+
+    async fn status_many(&self, gids: &[String]) -> Result<Vec<Result<Aria2Status, String>>, String> {
+        let mut statuses = vec![];
+        for gid in gids {
+            statuses.push(self.status(gid).await);
+        }
+        Ok(statuses)
+    }
+
+    async fn change_uris(&self, gid: &str, _old: &[String], new: &[String]) -> Result<(), String> {
+        *get_task(gid)?.urls.write().unwrap() = new.to_vec();
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Aria2Event> {
+        EVENTS.subscribe()
+    }
+}
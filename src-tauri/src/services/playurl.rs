@@ -0,0 +1,110 @@
+use serde::{Serialize, Deserialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri_plugin_http::reqwest::Url;
+use crate::{aria2c::{QueueInfo, QueueInfoURLs}, init_client};
+
+// Refresh links that would expire within this many seconds, so a task
+// doesn't die halfway through a large stream.
+const EXPIRY_MARGIN: u64 = 600;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PlayUrlResponse {
+    code: isize,
+    #[serde(default, alias = "msg")]
+    message: String,
+    data: Option<PlayUrlData>,
+    result: Option<PlayUrlData>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PlayUrlData {
+    dash: Option<Dash>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Dash {
+    video: Vec<DashStream>,
+    audio: Option<Vec<DashStream>>,
+    dolby: Option<DashDolby>,
+    flac: Option<DashFlac>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DashDolby {
+    audio: Option<Vec<DashStream>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DashFlac {
+    audio: Option<DashStream>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DashStream {
+    id: i32,
+    #[serde(alias = "baseUrl")]
+    base_url: String,
+    #[serde(default, alias = "backupUrl")]
+    backup_url: Option<Vec<String>>,
+    #[serde(default)]
+    codecid: Option<i32>,
+}
+
+impl DashStream {
+    fn urls(&self) -> Vec<String> {
+        let mut urls = vec![self.base_url.clone()];
+        urls.extend(self.backup_url.clone().unwrap_or_default());
+        urls
+    }
+}
+
+fn deadline(url: &str) -> Option<u64> {
+    Url::parse(url).ok()?.query_pairs()
+        .find(|(key, _)| key == "deadline" || key == "expires")
+        .and_then(|(_, value)| value.parse::<u64>().ok())
+}
+
+pub fn is_expired(url: &str) -> bool {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    deadline(url).is_some_and(|deadline| now + EXPIRY_MARGIN >= deadline)
+}
+
+pub async fn resolve(info: &QueueInfo) -> Result<QueueInfoURLs, String> {
+    let quality = info.quality.clone()
+        .ok_or(format!("No stored quality for {}, can't refresh its links", info.display_name))?;
+    let key = match info.media_type.as_str() {
+        "bangumi" => "pgc/player/web",
+        "lesson" => "pugv/player/web",
+        _ => "x/player"
+    };
+    let client = init_client().await?;
+    let resp = client.get(format!("https://api.bilibili.com/{}/playurl", key))
+        .query(&[
+            ("avid", info.id.to_string()), ("cid", info.cid.to_string()),
+            ("ep_id", info.eid.to_string()), ("fnval", "4048".into()),
+            ("fnver", "0".into()), ("fourk", "1".into())
+        ])
+        .send().await.map_err(|e| e.to_string())?;
+    let body: PlayUrlResponse = resp.json().await.map_err(|e| e.to_string())?;
+    if body.code != 0 {
+        return Err(format!("{}, {}", body.code, body.message));
+    }
+    let dash = body.data.or(body.result).and_then(|d| d.dash)
+        .ok_or(format!("No DASH streams for {}", info.display_name))?;
+    let video = if info.urls.video.is_empty() { vec![] } else {
+        dash.video.iter()
+            .filter(|v| v.id == quality.dms)
+            .max_by_key(|v| v.codecid == Some(quality.cdc))
+            .map(|v| v.urls())
+            .ok_or(format!("Quality {} is no longer available for {}", quality.dms, info.display_name))?
+    };
+    let audio = if info.urls.audio.is_empty() { vec![] } else {
+        dash.audio.unwrap_or_default().into_iter()
+            .chain(dash.dolby.and_then(|d| d.audio).unwrap_or_default())
+            .chain(dash.flac.and_then(|f| f.audio))
+            .find(|a| a.id == quality.ads)
+            .map(|a| a.urls())
+            .ok_or(format!("Audio quality {} is no longer available for {}", quality.ads, info.display_name))?
+    };
+    Ok(QueueInfoURLs { video, audio })
+}
@@ -0,0 +1,212 @@
+use std::{error::Error, net::IpAddr, time::Instant};
+use serde::{Serialize, Deserialize};
+use tauri_plugin_http::reqwest::{self, header::{HeaderMap, HeaderName, HeaderValue}, Client, Proxy, Url};
+use tokio::time::Duration;
+use crate::{handle_err, init_headers, SettingsProxy, CONFIG};
+
+const SCHEMES: [&str; 4] = ["http", "https", "socks5", "socks5h"];
+
+// Shorthands for rule patterns, so users don't need to know the endpoints
+const FAMILIES: [(&str, &str); 2] = [
+    ("pgc", "api.bilibili.com/pgc/"),
+    ("pugv", "api.bilibili.com/pugv/"),
+];
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ZoneResponse {
+    code: isize,
+    message: String,
+    data: Option<ZoneResponseData>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ZoneResponseData {
+    addr: String,
+    country: String,
+    #[serde(default)]
+    province: String,
+    #[serde(default)]
+    isp: String
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProxyTestResult {
+    latency: u128,
+    ip: String,
+    country: String,
+    province: String,
+    isp: String
+}
+
+// Addresses without a scheme are treated as HTTP proxies, like aria2 does.
+// Credentials travel in the URL, rules may each carry their own.
+pub fn proxy_url(proxy: &SettingsProxy) -> Result<Url, String> {
+    let addr = if proxy.addr.contains("://") { proxy.addr.clone() } else { format!("http://{}", proxy.addr) };
+    let mut url = Url::parse(&addr).map_err(|e| format!("Invalid proxy address {}: {}", proxy.addr, e))?;
+    if !SCHEMES.contains(&url.scheme()) {
+        return Err(format!("Unsupported proxy scheme {}, expected one of {}", url.scheme(), SCHEMES.join(", ")));
+    }
+    if url.host_str().is_none() {
+        return Err(format!("Invalid proxy address {}: missing host", proxy.addr));
+    }
+    if !proxy.username.is_empty() {
+        url.set_username(&proxy.username).map_err(|_| "Can't set proxy username".to_string())?;
+        url.set_password(Some(&proxy.password)).map_err(|_| "Can't set proxy password".to_string())?;
+    }
+    Ok(url)
+}
+
+pub fn validate(proxy: &SettingsProxy) -> Result<(), String> {
+    if !proxy.addr.is_empty() {
+        proxy_url(proxy)?;
+    }
+    for rule in &proxy.rules {
+        if rule.pattern.trim().is_empty() {
+            return Err("Proxy rules need a host or an API family to match".into());
+        }
+        if !rule.addr.is_empty() {
+            proxy_url(&rule.proxy())?;
+        }
+    }
+    Ok(())
+}
+
+// "pgc", "*.bilivideo.com", "api.bilibili.com/pgc/" and the like
+fn matches(pattern: &str, url: &Url) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    let pattern = FAMILIES.iter()
+        .find(|(family, _)| *family == pattern)
+        .map_or(pattern.as_str(), |(_, expanded)| expanded);
+    let (host_pattern, path) = pattern.split_once('/').unwrap_or((pattern, ""));
+    let Some(host) = url.host_str().map(|h| h.to_lowercase()) else { return false };
+    let host_matches = match host_pattern.strip_prefix("*.") {
+        Some(domain) => host == domain || host.ends_with(&format!(".{}", domain)),
+        None => host == host_pattern
+    };
+    host_matches && url.path().trim_start_matches('/').starts_with(path)
+}
+
+fn bypassed(no_proxy: &str, url: &Url) -> bool {
+    let Some(host) = url.host_str().map(|h| h.trim_matches(['[', ']']).to_lowercase()) else { return false };
+    let ip = host.parse::<IpAddr>().ok();
+    no_proxy.split(',').map(str::trim).filter(|entry| !entry.is_empty()).any(|entry| {
+        if entry == "*" { return true; }
+        if let (Some(ip), Some((net, bits))) = (ip, entry.split_once('/')) {
+            return match (ip, net.parse::<IpAddr>(), bits.parse::<u32>()) {
+                (IpAddr::V4(ip), Ok(IpAddr::V4(net)), Ok(bits)) if bits <= 32 =>
+                    u32::from(ip).checked_shr(32 - bits).unwrap_or(0) == u32::from(net).checked_shr(32 - bits).unwrap_or(0),
+                (IpAddr::V6(ip), Ok(IpAddr::V6(net)), Ok(bits)) if bits <= 128 =>
+                    u128::from(ip).checked_shr(128 - bits).unwrap_or(0) == u128::from(net).checked_shr(128 - bits).unwrap_or(0),
+                _ => false
+            };
+        }
+        let domain = entry.trim_start_matches("*.").trim_start_matches('.').to_lowercase();
+        host == domain || host.ends_with(&format!(".{}", domain))
+    })
+}
+
+// The first matching rule wins, an empty address on a rule means direct.
+// Everything else takes the default proxy unless it's on the bypass list.
+pub fn route(proxy: &SettingsProxy, url: &Url) -> Option<SettingsProxy> {
+    match proxy.rules.iter().find(|rule| matches(&rule.pattern, url)) {
+        Some(rule) => (!rule.addr.is_empty()).then(|| rule.proxy()),
+        None => (!proxy.addr.is_empty() && !bypassed(&proxy.no_proxy, url)).then(|| proxy.clone())
+    }
+}
+
+pub fn build(proxy: &SettingsProxy) -> Result<Option<Proxy>, String> {
+    validate(proxy)?;
+    if proxy.addr.is_empty() && proxy.rules.is_empty() { return Ok(None); }
+    let proxy = proxy.clone();
+    Ok(Some(Proxy::custom(move |url| route(&proxy, url).and_then(|p| proxy_url(&p).ok()))))
+}
+
+// reqwest's Display stops at the outermost error, the useful part
+// ("Connection refused", "SOCKS authentication failed", ...) is further down.
+fn describe(e: &reqwest::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(inner) = source {
+        message = format!("{}: {}", message, inner);
+        source = inner.source();
+    }
+    if e.is_timeout() {
+        format!("Timed out: {}", message)
+    } else if e.is_connect() {
+        format!("Can't connect: {}", message)
+    } else { message }
+}
+
+#[tauri::command]
+pub async fn test_proxy() -> Result<ProxyTestResult, String> {
+    let proxy = CONFIG.read().unwrap().proxy.clone();
+    if proxy.addr.is_empty() {
+        return Err(handle_err("No proxy configured"));
+    }
+    // Skip the rules and the bypass list, the point is to go through the proxy
+    let url = proxy_url(&proxy).map_err(handle_err)?;
+    let proxy = Proxy::all(url.as_str()).map_err(handle_err)?;
+    let mut headers = HeaderMap::new();
+    for (key, value) in init_headers().await? {
+    headers.insert(
+        HeaderName::from_bytes(key.as_bytes()).unwrap(),
+        HeaderValue::from_str(&value).unwrap()
+    ); }
+    let client = Client::builder()
+        .default_headers(headers)
+        .proxy(proxy)
+        .timeout(Duration::from_secs(10))
+        .build().map_err(handle_err)?;
+    let start = Instant::now();
+    let resp = client.get("https://api.bilibili.com/x/web-interface/zone")
+        .send().await.map_err(|e| handle_err(describe(&e)))?;
+    let latency = start.elapsed().as_millis();
+    if !resp.status().is_success() {
+        return Err(handle_err(format!("Proxy answered with {}", resp.status())));
+    }
+    let body: ZoneResponse = resp.json().await.map_err(|e| handle_err(describe(&e)))?;
+    let data = body.data.ok_or_else(|| handle_err(format!("{}, {}", body.code, body.message)))?;
+    Ok(ProxyTestResult {
+        latency,
+        ip: data.addr,
+        country: data.country,
+        province: data.province,
+        isp: data.isp
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bypass_list_matches_domains_and_cidr_ranges() {
+        let cases = [
+            ("bilivideo.com", "https://upos.bilivideo.com/", true),
+            (".bilivideo.com", "https://bilivideo.com/", true),
+            ("*.bilivideo.com", "https://upos.bilivideo.com/", true),
+            ("bilivideo.com", "https://notbilivideo.com/", false),
+            ("*", "https://api.bilibili.com/", true),
+            ("10.0.0.0/8", "http://10.1.2.3/", true),
+            ("10.0.0.0/8", "http://11.1.2.3/", false),
+            ("0.0.0.0/0", "http://1.2.3.4/", true),
+            ("fd00::/8", "http://[fd12::1]/", true),
+            ("fd00::/8", "http://[fe80::1]/", false),
+            ("10.0.0.0/8", "http://[fd12::1]/", false),
+        ];
+        for (no_proxy, url, expected) in cases {
+            assert_eq!(bypassed(no_proxy, &Url::parse(url).unwrap()), expected, "{} with no_proxy {}", url, no_proxy);
+        }
+    }
+
+    #[test]
+    fn rules_match_hosts_families_and_paths() {
+        let url = Url::parse("https://api.bilibili.com/pgc/player/web/playurl").unwrap();
+        assert!(matches("pgc", &url));
+        assert!(matches("api.bilibili.com/pgc/", &url));
+        assert!(matches("*.bilibili.com", &url));
+        assert!(!matches("pugv", &url));
+        assert!(!matches("api.bilibili.com/x/", &url));
+        assert!(!matches("*.bilivideo.com", &url));
+    }
+}
@@ -1,17 +1,22 @@
-
-use std::error::Error;
+use std::{error::Error, fs, path::Path, time::{SystemTime, UNIX_EPOCH}};
 use serde::{Serialize, Deserialize};
-use sea_orm::{Database, DbBackend, Set, Schema, Statement};
+use sea_orm::{Condition, Database, DatabaseConnection, DbBackend, PaginatorTrait, QueryOrder, Set, Schema, Statement, TransactionTrait};
 use sea_orm::entity::prelude::*;
 use sea_orm::sea_query::{TableCreateStatement, SqliteQueryBuilder};
+use rand::{distributions::Alphanumeric, Rng};
 use crate::{aria2c::{VideoInfo, COMPLETE_QUEUE}, services::STORAGE_PATH};
 
 #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "downloads")]
 pub struct Model {
-    #[sea_orm(primary_key, auto_increment = false)]
-    name: String,
-    value: VideoInfo,
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub task_id: String,
+    pub title: String,
+    pub display_name: String,
+    pub upper: String,
+    pub completed_at: i64,
+    pub value: VideoInfo,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -19,35 +24,174 @@ pub enum Relation {}
 
 impl ActiveModelBehavior for ActiveModel {}
 
-pub async fn init() -> Result<(), Box<dyn Error>> {
-    let db = Database::connect(format!("sqlite://{}", STORAGE_PATH.display())).await?;
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HistoryQuery {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub upper: String,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    #[serde(default)]
+    pub page: u64,
+    #[serde(default)]
+    pub page_size: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoryPage {
+    pub items: Vec<Model>,
+    pub total: u64,
+    pub page: u64,
+    pub pages: u64,
+}
+
+async fn connect() -> Result<DatabaseConnection, Box<dyn Error>> {
+    Ok(Database::connect(format!("sqlite://{}", STORAGE_PATH.display())).await?)
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+fn active_model(info: VideoInfo, completed_at: i64) -> ActiveModel {
+    ActiveModel {
+        task_id: Set(info.id.clone()),
+        title: Set(info.queue_info.title.clone()),
+        display_name: Set(info.display_name.clone()),
+        upper: Set(info.queue_info.upper.clone()),
+        completed_at: Set(completed_at),
+        value: Set(info),
+        ..Default::default()
+    }
+}
+
+// Older versions keyed this table by display_name, so two episodes sharing a
+// name couldn't both be recorded. Move those rows over to the new layout.
+async fn migrate(db: &DatabaseConnection) -> Result<(), Box<dyn Error>> {
+    let columns = db.query_all(Statement::from_string(
+        DbBackend::Sqlite,
+        "PRAGMA table_info(downloads)"
+    )).await?;
+    let legacy = columns.iter().any(|row| row.try_get::<String>("", "name").is_ok_and(|name| name == "name"));
+    if !legacy { return Ok(()); }
+    log::info!("Migrating download history to the new layout");
+    // All or nothing, a half-done migration would be taken for a finished one
+    let txn = db.begin().await?;
+    txn.execute(Statement::from_string(DbBackend::Sqlite, "ALTER TABLE downloads RENAME TO downloads_legacy")).await?;
+    create_table(&txn).await?;
+    let rows = txn.query_all(Statement::from_string(DbBackend::Sqlite, "SELECT value FROM downloads_legacy")).await?;
+    for row in rows {
+        let mut info: VideoInfo = row.try_get("", "value")?;
+        if info.id.is_empty() {
+            info.id = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(16).map(char::from)
+                .collect();
+        }
+        let completed_at = fs::metadata(&info.output_path).and_then(|m| m.modified()).ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs() as i64);
+        active_model(info, completed_at).insert(&txn).await?;
+    }
+    txn.execute(Statement::from_string(DbBackend::Sqlite, "DROP TABLE downloads_legacy")).await?;
+    txn.commit().await?;
+    Ok(())
+}
+
+async fn create_table(db: &impl ConnectionTrait) -> Result<(), Box<dyn Error>> {
     let schema = Schema::new(DbBackend::Sqlite);
     let stmt: TableCreateStatement = schema.create_table_from_entity(Entity).if_not_exists().to_owned();
     db.execute(Statement::from_string(
-        DbBackend::Sqlite, 
+        DbBackend::Sqlite,
         stmt.to_string(SqliteQueryBuilder)
     )).await?;
+    Ok(())
+}
+
+pub async fn init() -> Result<(), Box<dyn Error>> {
+    let db = connect().await?;
+    migrate(&db).await?;
+    create_table(&db).await?;
     load().await?;
     Ok(())
 }
 
 pub async fn insert(info: VideoInfo) -> Result<(), Box<dyn Error>> {
-    let db = Database::connect(format!("sqlite://{}", STORAGE_PATH.display())).await?;
-    let db_info = ActiveModel {
-        name: Set(info.display_name.clone()),
-        value: Set(info)
-    };
-    db_info.insert(&db).await?;
+    let db = connect().await?;
+    active_model(info, now()).insert(&db).await?;
+    Ok(())
+}
+
+// Skips entries whose task is already recorded, returns how many were added
+pub async fn merge(entries: Vec<(VideoInfo, i64)>) -> Result<usize, Box<dyn Error>> {
+    let db = connect().await?;
+    let mut added = 0;
+    for (info, completed_at) in entries {
+        if Entity::find().filter(Column::TaskId.eq(&info.id)).count(&db).await? > 0 { continue; }
+        active_model(info, completed_at).insert(&db).await?;
+        added += 1;
+    }
+    Ok(added)
+}
+
+pub async fn all() -> Result<Vec<Model>, Box<dyn Error>> {
+    let db = connect().await?;
+    Ok(Entity::find().order_by_asc(Column::CompletedAt).order_by_asc(Column::Id).all(&db).await?)
+}
+
+pub async fn find_by_output(path: &Path) -> Result<Option<Model>, Box<dyn Error>> {
+    let db = connect().await?;
+    Ok(Entity::find().order_by_desc(Column::CompletedAt).all(&db).await?
+        .into_iter().find(|entry| entry.value.output_path == path))
+}
+
+pub async fn find(id: i32) -> Result<Option<Model>, Box<dyn Error>> {
+    let db = connect().await?;
+    Ok(Entity::find_by_id(id).one(&db).await?)
+}
+
+pub async fn delete(task_id: &str) -> Result<(), Box<dyn Error>> {
+    let db = connect().await?;
+    Entity::delete_many().filter(Column::TaskId.eq(task_id)).exec(&db).await?;
     Ok(())
 }
 
+pub async fn search(query: HistoryQuery) -> Result<HistoryPage, Box<dyn Error>> {
+    let db = connect().await?;
+    let mut select = Entity::find().order_by_desc(Column::CompletedAt).order_by_desc(Column::Id);
+    if !query.title.is_empty() {
+        select = select.filter(Condition::any()
+            .add(Column::Title.contains(&query.title))
+            .add(Column::DisplayName.contains(&query.title)));
+    }
+    if !query.upper.is_empty() {
+        select = select.filter(Column::Upper.contains(&query.upper));
+    }
+    if let Some(from) = query.from {
+        select = select.filter(Column::CompletedAt.gte(from));
+    }
+    if let Some(to) = query.to {
+        select = select.filter(Column::CompletedAt.lte(to));
+    }
+    let page_size = if query.page_size == 0 { 20 } else { query.page_size };
+    let paginator = select.paginate(&db, page_size);
+    let totals = paginator.num_items_and_pages().await?;
+    Ok(HistoryPage {
+        items: paginator.fetch_page(query.page).await?,
+        total: totals.number_of_items,
+        page: query.page,
+        pages: totals.number_of_pages,
+    })
+}
+
 pub async fn load() -> Result<(), Box<dyn Error>> {
-    let db = Database::connect(format!("sqlite://{}", STORAGE_PATH.display())).await?;
-    let downloads = Entity::find().all(&db).await?;
+    let db = connect().await?;
+    let downloads = Entity::find().order_by_asc(Column::CompletedAt).all(&db).await?;
     let mut complete_queue = COMPLETE_QUEUE.lock().await;
     complete_queue.clear();
     for download in downloads {
         complete_queue.push_back(download.value);
     }
     Ok(())
-}
\ No newline at end of file
+}
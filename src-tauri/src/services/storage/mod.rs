@@ -1,5 +1,6 @@
 pub mod cookies;
 pub mod downloads;
+pub mod queue;
 pub mod config;
 
 use std::{collections::HashMap, error::Error, fs};
@@ -28,5 +29,6 @@ pub async fn init() -> Result<(), Box<dyn Error>> {
     migrate().await?;
     cookies::init().await?;
     downloads::init().await?;
+    queue::init().await?;
     Ok(())
 }
\ No newline at end of file
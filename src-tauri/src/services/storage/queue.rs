@@ -0,0 +1,71 @@
+use std::{collections::VecDeque, error::Error};
+use serde::{Serialize, Deserialize};
+use sea_orm::{Database, DbBackend, Set, Schema, Statement, QueryOrder, TransactionTrait};
+use sea_orm::entity::prelude::*;
+use sea_orm::sea_query::{TableCreateStatement, SqliteQueryBuilder};
+use crate::{aria2c::{VideoInfo, WAITING_QUEUE, DOING_QUEUE, FAILED_QUEUE}, services::STORAGE_PATH};
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "queue")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    id: String,
+    state: String,
+    position: i32,
+    value: VideoInfo,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+pub async fn init() -> Result<(), Box<dyn Error>> {
+    let db = Database::connect(format!("sqlite://{}", STORAGE_PATH.display())).await?;
+    let schema = Schema::new(DbBackend::Sqlite);
+    let stmt: TableCreateStatement = schema.create_table_from_entity(Entity).if_not_exists().to_owned();
+    db.execute(Statement::from_string(
+        DbBackend::Sqlite,
+        stmt.to_string(SqliteQueryBuilder)
+    )).await?;
+    load().await?;
+    Ok(())
+}
+
+pub async fn save(waiting: &VecDeque<VideoInfo>, doing: &VecDeque<VideoInfo>, failed: &VecDeque<VideoInfo>) -> Result<(), Box<dyn Error>> {
+    let db = Database::connect(format!("sqlite://{}", STORAGE_PATH.display())).await?;
+    // One transaction, a failed insert must not leave the stored queue empty
+    let txn = db.begin().await?;
+    Entity::delete_many().exec(&txn).await?;
+    let rows = [("waiting", waiting), ("doing", doing), ("failed", failed)].into_iter()
+        .flat_map(|(state, queue)| queue.iter().enumerate().map(move |(position, info)| ActiveModel {
+            id: Set(info.id.clone()),
+            state: Set(state.into()),
+            position: Set(position as i32),
+            value: Set(info.clone())
+        })).collect::<Vec<_>>();
+    if !rows.is_empty() {
+        Entity::insert_many(rows).exec(&txn).await?;
+    }
+    txn.commit().await?;
+    Ok(())
+}
+
+pub async fn load() -> Result<(), Box<dyn Error>> {
+    let db = Database::connect(format!("sqlite://{}", STORAGE_PATH.display())).await?;
+    let rows = Entity::find().order_by_asc(Column::Position).all(&db).await?;
+    let mut waiting_queue = WAITING_QUEUE.lock().await;
+    let mut doing_queue = DOING_QUEUE.lock().await;
+    let mut failed_queue = FAILED_QUEUE.lock().await;
+    waiting_queue.clear();
+    doing_queue.clear();
+    failed_queue.clear();
+    for row in rows {
+        match row.state.as_str() {
+            "doing" => doing_queue.push_back(row.value),
+            "failed" => failed_queue.push_back(row.value),
+            _ => waiting_queue.push_back(row.value),
+        }
+    }
+    Ok(())
+}
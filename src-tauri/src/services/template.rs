@@ -0,0 +1,240 @@
+use std::path::{Path, PathBuf};
+use chrono::Local;
+use crate::{aria2c::QueueInfo, ffmpeg};
+
+// Longest path we let through. Windows without long path support stops at
+// MAX_PATH (260 including the terminator), everyone else allows 4096 bytes.
+#[cfg(windows)]
+const MAX_PATH_LEN: usize = 259;
+#[cfg(not(windows))]
+const MAX_PATH_LEN: usize = 4095;
+const MAX_COMPONENT_LEN: usize = 255;
+// Room the file name keeps for the ".part" finalize writes to first and the
+// " (N)" a rename on conflict appends
+const NAME_HEADROOM: usize = 16;
+
+const FIELDS: [&str; 17] = [
+    "title", "display_name", "ss_title", "upper", "media_type", "id", "cid", "eid",
+    "quality", "audio_quality", "dms", "ads", "cdc", "codec", "date", "time", "ext"
+];
+
+const RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"
+];
+
+fn quality_name(dms: i32) -> String {
+    match dms {
+        6 => "240P", 16 => "360P", 32 => "480P", 64 => "720P", 74 => "720P60",
+        80 => "1080P", 112 => "1080P+", 116 => "1080P60", 120 => "4K",
+        125 => "HDR", 126 => "Dolby Vision", 127 => "8K",
+        _ => return dms.to_string()
+    }.into()
+}
+
+fn audio_quality_name(ads: i32) -> String {
+    match ads {
+        30216 => "64K", 30232 => "132K", 30280 => "192K",
+        30250 => "Dolby Atmos", 30251 => "Hi-Res",
+        _ => return ads.to_string()
+    }.into()
+}
+
+fn codec_name(cdc: i32) -> String {
+    match cdc {
+        7 => "AVC", 12 => "HEVC", 13 => "AV1",
+        _ => return cdc.to_string()
+    }.into()
+}
+
+pub fn extension(info: &QueueInfo) -> &'static str {
+    // Only merged files take the chosen container, a lone video stream stays MP4
+    if !info.urls.video.is_empty() {
+        if info.urls.audio.is_empty() { return "mp4"; }
+        return ffmpeg::container(&info.container).map_or("mp4", |c| c.ext);
+    }
+    // Audio is moved without remuxing, and even Hi-Res FLAC comes as fragmented MP4
+    "m4a"
+}
+
+fn field(info: &QueueInfo, name: &str) -> Option<String> {
+    let quality = info.quality.clone().unwrap_or_default();
+    Some(match name {
+        "title" => info.title.clone(),
+        "display_name" => info.display_name.clone(),
+        "ss_title" => info.ss_title.clone(),
+        "upper" => info.upper.clone(),
+        "media_type" => info.media_type.clone(),
+        "id" => info.id.to_string(),
+        "cid" => info.cid.to_string(),
+        "eid" => info.eid.to_string(),
+        "quality" => if info.urls.video.is_empty() { audio_quality_name(quality.ads) } else { quality_name(quality.dms) },
+        "audio_quality" => audio_quality_name(quality.ads),
+        "dms" => quality.dms.to_string(),
+        "ads" => quality.ads.to_string(),
+        "cdc" => quality.cdc.to_string(),
+        "codec" => codec_name(quality.cdc),
+        "date" => Local::now().format("%Y-%m-%d").to_string(),
+        "time" => Local::now().format("%H-%M-%S").to_string(),
+        "ext" => extension(info).into(),
+        _ => return None
+    })
+}
+
+// `{eid:02}` pads with zeros, `{eid:3}` with spaces
+fn pad(value: String, spec: &str) -> Result<String, String> {
+    if spec.is_empty() { return Ok(value); }
+    let width = spec.parse::<usize>().map_err(|_| format!("Invalid format spec :{}", spec))?;
+    Ok(if spec.starts_with('0') {
+        format!("{:0>width$}", value, width = width)
+    } else {
+        format!("{:>width$}", value, width = width)
+    })
+}
+
+fn expand(template: &str, mut lookup: impl FnMut(&str) -> Option<String>) -> Result<String, String> {
+    let mut output = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        let end = rest[start..].find('}')
+            .ok_or(format!("Unclosed {{ in template {}", template))? + start;
+        let placeholder = &rest[start + 1..end];
+        let (name, spec) = placeholder.split_once(':').unwrap_or((placeholder, ""));
+        let value = lookup(name).ok_or(format!("Unknown template field {{{}}}", name))?;
+        output.push_str(&pad(value, spec)?);
+        rest = &rest[end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+fn truncate(value: &str, max_bytes: usize) -> String {
+    let mut end = value.len().min(max_bytes);
+    while !value.is_char_boundary(end) { end -= 1; }
+    value[..end].to_string()
+}
+
+// Makes one path component valid on Windows, macOS and Linux alike, so a
+// library copied between machines keeps working.
+fn sanitize(component: &str) -> String {
+    let cleaned: String = component.chars()
+        .map(|c| if c.is_control() || "<>:\"/\\|?*".contains(c) { '_' } else { c })
+        .collect();
+    let mut cleaned = cleaned.trim().trim_end_matches(['.', ' ']).to_string();
+    if cleaned.is_empty() || cleaned.chars().all(|c| c == '.') {
+        cleaned = "_".into();
+    }
+    let stem = cleaned.split('.').next().unwrap_or_default().to_uppercase();
+    if RESERVED.contains(&stem.as_str()) {
+        cleaned.insert(0, '_');
+    }
+    cleaned
+}
+
+// Shortens the name but never eats into the extension
+fn shorten(name: &str, max_bytes: usize) -> String {
+    if name.len() <= max_bytes { return name.to_string(); }
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if ext.len() < 16 && ext.len() + 1 < max_bytes =>
+            format!("{}.{}", truncate(stem, max_bytes - ext.len() - 1).trim_end(), ext),
+        _ => truncate(name, max_bytes)
+    }
+}
+
+pub fn validate(template: &str) -> Result<(), String> {
+    if template.trim().is_empty() {
+        return Err("The filename template can't be empty".into());
+    }
+    expand(template, |name| FIELDS.contains(&name).then(String::new)).map(|_| ())
+}
+
+pub fn render(template: &str, base: &Path, info: &QueueInfo) -> Result<PathBuf, String> {
+    // Expand each component on its own, so a "/" inside a title can't add a level
+    let mut components = template.split(['/', '\\'])
+        .filter(|c| !c.trim().is_empty())
+        .map(|c| expand(c, |name| field(info, name)).map(|c| shorten(&sanitize(&c), MAX_COMPONENT_LEN)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let name = components.pop().ok_or("The filename template has no file name")?;
+    let dir = components.iter().fold(base.to_path_buf(), |path, c| path.join(c));
+    let budget = MAX_PATH_LEN.saturating_sub(dir.to_string_lossy().len() + 1 + NAME_HEADROOM);
+    if budget < 16 {
+        return Err(format!("Output directory {} is too long to fit a file name", dir.display()));
+    }
+    Ok(dir.join(shorten(&name, budget.min(MAX_COMPONENT_LEN - NAME_HEADROOM))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aria2c::QueueInfoURLs;
+
+    fn info(title: &str) -> QueueInfo {
+        QueueInfo {
+            title: title.into(),
+            display_name: title.into(),
+            ss_title: "Series".into(),
+            eid: 3,
+            urls: QueueInfoURLs { video: vec!["v".into()], audio: vec!["a".into()] },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sanitize_replaces_illegal_characters() {
+        assert_eq!(sanitize("a<b>c:d\"e|f?g*h"), "a_b_c_d_e_f_g_h");
+        assert_eq!(sanitize("line\nbreak"), "line_break");
+    }
+
+    #[test]
+    fn sanitize_trims_dots_and_spaces() {
+        assert_eq!(sanitize("  name. . "), "name");
+        assert_eq!(sanitize("..."), "_");
+        assert_eq!(sanitize(""), "_");
+    }
+
+    #[test]
+    fn sanitize_escapes_reserved_names() {
+        assert_eq!(sanitize("CON"), "_CON");
+        assert_eq!(sanitize("com1.mp4"), "_com1.mp4");
+        assert_eq!(sanitize("CONSOLE"), "CONSOLE");
+    }
+
+    #[test]
+    fn shorten_keeps_the_extension() {
+        assert_eq!(shorten("abcdefghij.mp4", 8), "abcd.mp4");
+        assert_eq!(shorten("short.mp4", 64), "short.mp4");
+    }
+
+    #[test]
+    fn shorten_respects_char_boundaries() {
+        let name = format!("{}.mp4", "哔".repeat(10));
+        let short = shorten(&name, 12);
+        assert!(short.len() <= 12);
+        assert_eq!(short, "哔哔.mp4");
+    }
+
+    #[test]
+    fn render_pads_and_keeps_titles_in_one_component() {
+        let base = Path::new("/downloads");
+        let path = render("{ss_title}/{eid:02} {title}.{ext}", base, &info("a/b")).unwrap();
+        assert_eq!(path, base.join("Series").join("03 a_b.mp4"));
+    }
+
+    #[test]
+    fn render_caps_component_length() {
+        let base = Path::new("/downloads");
+        let path = render("{title}.{ext}", base, &info(&"x".repeat(400))).unwrap();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        assert_eq!(name.len(), MAX_COMPONENT_LEN - NAME_HEADROOM);
+        assert!(name.ends_with(".mp4"));
+        assert!(format!("{} (99).part", name).len() <= MAX_COMPONENT_LEN);
+    }
+
+    #[test]
+    fn render_rejects_unknown_fields() {
+        assert!(render("{nope}", Path::new("/downloads"), &info("a")).is_err());
+        assert!(validate("{title").is_err());
+    }
+}
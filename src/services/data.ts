@@ -3,14 +3,13 @@ import { fetch } from '@tauri-apps/plugin-http';
 import { invoke } from '@tauri-apps/api/core';
 import * as types from '@/types';
 import store from '@/store';
-import { formatProxyUrl } from '@/services/utils';
+import { formatProxyUrl, proxyFor } from '@/services/utils';
 
 async function getResponse(baseURL: string, type: types.data.MediaType) {
+    const proxy = proxyFor(baseURL, store.state.settings.proxy);
     const response = await fetch(baseURL, {
         headers: store.state.data.headers,
-        ...(store.state.settings.proxy.addr && {
-            proxy: { all: formatProxyUrl(store.state.settings.proxy) }
-        })
+        ...(proxy && { proxy: { all: proxy } })
     });
     const data = await response.json();
     if (data?.code !== 0) throw data;
@@ -64,7 +63,7 @@ export async function getMediaInfo<T extends types.data.MediaType>(rawId: string
                             mid: info?.owner?.mid
                         },
                         list: info?.ugc_season ? 
-                            info?.ugc_season?.sections[0]?.episodes?.map(episode => ({
+                            info?.ugc_season?.sections[0]?.episodes?.map((episode, index) => ({
                                 title: episode?.title,
                                 desc: episode?.arc?.desc,
                                 cover: episode?.arc?.pic?.replace("http:", "https:"),
@@ -72,9 +71,10 @@ export async function getMediaInfo<T extends types.data.MediaType>(rawId: string
                                 id: episode?.aid,
                                 cid: episode?.cid,
                                 eid: episode?.id,
+                                index: index + 1,
                                 ss_title: info?.ugc_season?.title
                             })) :
-                            info?.pages?.map(page => ({
+                            info?.pages?.map((page, index) => ({
                                 title: page?.part || info?.title,
                                 desc: info?.desc,
                                 cover: info?.pic?.replace("http:", "https:"),
@@ -82,6 +82,7 @@ export async function getMediaInfo<T extends types.data.MediaType>(rawId: string
                                 id: info?.aid,
                                 cid: page?.cid,
                                 eid: page?.page,
+                                index: index + 1,
                                 ss_title: info?.title || page?.part
                             }))
                     };
@@ -111,7 +112,7 @@ export async function getMediaInfo<T extends types.data.MediaType>(rawId: string
                             name: info?.up_info?.uname,
                             mid: info?.up_info?.mid
                         },
-                        list: info?.episodes?.map(episode => ({
+                        list: info?.episodes?.map((episode, index) => ({
                             title: episode.share_copy,
                             desc: info?.evaluate,
                             cover: episode.cover?.replace("http:", "https:"),
@@ -119,6 +120,7 @@ export async function getMediaInfo<T extends types.data.MediaType>(rawId: string
                             id: episode.aid,
                             cid: episode.cid,
                             eid: episode.ep_id,
+                            index: index + 1,
                             ss_title: info?.season_title
                         }))
                     };
@@ -183,7 +185,7 @@ export async function getMediaInfo<T extends types.data.MediaType>(rawId: string
                             name: info?.up_info?.uname,
                             mid: info?.up_info?.mid
                         },
-                        list: info?.episodes?.map(episode => ({
+                        list: info?.episodes?.map((episode, index) => ({
                             title: episode.title,
                             desc: info?.subtitle,
                             cover: episode.cover?.replace("http:", "https:"),
@@ -191,6 +193,7 @@ export async function getMediaInfo<T extends types.data.MediaType>(rawId: string
                             id: episode.aid,
                             cid: episode.cid,
                             eid: episode.id,
+                            index: index + 1,
                             ss_title: info?.title
                         }))
                     };
@@ -216,11 +219,10 @@ export async function getPlayUrl(data: types.data.MediaInfo["list"][0], type: ty
         }
         const key = type == "bangumi" ? "pgc/player/web" : (type == "lesson" ? "pugv/player/web" : "x/player/wbi");
         const basicUrl = `https://api.bilibili.com/${key}/playurl?${await auth.wbi(params)}`;
+        const proxy = proxyFor(basicUrl, store.state.settings.proxy);
         const resp = await fetch(basicUrl, {
             headers: store.state.data.headers,
-            ...(store.state.settings.proxy.addr && {
-                proxy: { all: formatProxyUrl(store.state.settings.proxy) }
-            })
+            ...(proxy && { proxy: { all: proxy } })
         });
         const basicResp = await resp.json();
         if (basicResp?.code !== 0) {
@@ -246,7 +248,12 @@ export async function getPlayUrl(data: types.data.MediaInfo["list"][0], type: ty
 export async function pushBackQueue(queueInfo: types.data.QueueInfo): Promise<void> {
     return new Promise(async (resolve, reject) => {
         try {
-            const gids = await invoke('push_back_queue', { queueInfo }) as types.data.QueueInfo["gids"];
+            const result = await invoke('push_back_queue', { queueInfo }) as types.data.QueueInfo["gids"] & { skipped?: string };
+            if (result?.skipped) {
+                utils.iziInfo(result.skipped);
+                return resolve();
+            }
+            const gids = result;
             console.log({ ...queueInfo, gids })
             store.commit('pushToArray', { 'queue.waiting': { ...queueInfo, gids } });
             return resolve();
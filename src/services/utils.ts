@@ -202,3 +202,66 @@ export function formatProxyUrl(proxy: { addr: string, username?: string, passwor
     url.password = proxy.password || '';
     return url.toString();
 }
+
+const PROXY_FAMILIES: Record<string, string> = {
+    pgc: 'api.bilibili.com/pgc/',
+    pugv: 'api.bilibili.com/pugv/',
+};
+
+function matchProxyRule(pattern: string, target: URL): boolean {
+    let rule = pattern.trim().toLowerCase();
+    rule = PROXY_FAMILIES[rule] ?? rule;
+    const [hostPattern, ...path] = rule.split('/');
+    const host = target.hostname.toLowerCase();
+    const hostMatches = hostPattern.startsWith('*.')
+        ? host === hostPattern.slice(2) || host.endsWith(hostPattern.slice(1))
+        : host === hostPattern;
+    return hostMatches && target.pathname.replace(/^\//, '').startsWith(path.join('/'));
+}
+
+function parseIp(ip: string): { v6: boolean, value: bigint } | null {
+    if (/^\d{1,3}(\.\d{1,3}){3}$/.test(ip)) {
+        const parts = ip.split('.').map(Number);
+        if (parts.some(part => part > 255)) return null;
+        return { v6: false, value: parts.reduce((acc, part) => (acc << 8n) + BigInt(part), 0n) };
+    }
+    const halves = ip.split('::');
+    if (!ip.includes(':') || halves.length > 2) return null;
+    const groups = halves.map(half => half ? half.split(':') : []);
+    const missing = 8 - groups.flat().length;
+    if (missing < 0 || (halves.length === 1 && missing !== 0)) return null;
+    const all = halves.length === 2 ? [...groups[0], ...Array(missing).fill('0'), ...groups[1]] : groups[0];
+    if (all.some(group => !/^[0-9a-f]{1,4}$/i.test(group))) return null;
+    return { v6: true, value: all.reduce((acc, group) => (acc << 16n) + BigInt(parseInt(group, 16)), 0n) };
+}
+
+function bypassProxy(noProxy: string, target: URL): boolean {
+    const host = target.hostname.replace(/^\[|\]$/g, '').toLowerCase();
+    const ip = parseIp(host);
+    return noProxy.split(',').map(entry => entry.trim()).filter(Boolean).some(entry => {
+        if (entry === '*') return true;
+        const [net, bits] = entry.split('/');
+        if (ip && bits !== undefined) {
+            const network = parseIp(net);
+            const width = ip.v6 ? 128n : 32n;
+            const prefix = BigInt(Number(bits));
+            if (!network || network.v6 !== ip.v6 || !/^\d+$/.test(bits) || prefix > width) return false;
+            return (ip.value >> (width - prefix)) === (network.value >> (width - prefix));
+        }
+        const domain = entry.replace(/^\*\./, '').replace(/^\./, '').toLowerCase();
+        return host === domain || host.endsWith('.' + domain);
+    });
+}
+
+// Mirrors the backend's routing, including the bypass list, returns an
+// empty string to go direct
+export function proxyFor(url: string, proxy: {
+    addr: string, username?: string, password?: string, no_proxy?: string,
+    rules?: { pattern: string, addr: string, username?: string, password?: string }[]
+}): string {
+    const target = new URL(url);
+    const rule = proxy.rules?.find(rule => matchProxyRule(rule.pattern, target));
+    if (rule) return rule.addr ? formatProxyUrl(rule) : '';
+    if (!proxy.addr || bypassProxy(proxy.no_proxy ?? '', target)) return '';
+    return formatProxyUrl(proxy);
+}
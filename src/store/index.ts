@@ -25,10 +25,35 @@ export default createStore({
                 df_ads: 30280,
                 df_cdc: 7,
                 auto_check_update: false,
+                max_retries: 3,
+                retry_backoff: 2,
+                downloader: 'aria2',
+                disk_check: 'refuse',
+                filename_template: '{ss_title}/{display_name}.{ext}',
+                on_conflict: 'skip',
+                container: 'mp4',
+                audio_mode: 'transcode',
+                down_limit: 0,
+                task_down_limit: 0,
+                aria2: {
+                    split: 20,
+                    max_connection_per_server: 5,
+                    min_split_size: 10,
+                    disk_cache: 32,
+                    file_allocation: 'none',
+                },
+                schedule: {
+                    mode: 'off',
+                    start: '01:00',
+                    end: '07:00',
+                    limit: 0,
+                },
                 proxy: {
                     addr: '',
                     username: '',
                     password: '',
+                    no_proxy: '',
+                    rules: [],
                 },
             },
             data: {
@@ -19,6 +19,8 @@ export interface MediaInfoListItem {
   id: number,
   cid: number,
   eid: number,
+  // 1-based position in the season or list
+  index?: number,
   ss_title: string;
 }
 
@@ -57,6 +59,16 @@ export interface QueueInfo extends MediaInfoListItem {
   gids?: {
     vgid?: string,
     agid?: string,
+  },
+  media_type?: MediaType,
+  upper?: string,
+  pubdate?: string,
+  container?: 'mp4' | 'mkv' | 'mov',
+  audio_mode?: 'copy' | 'transcode',
+  quality?: {
+    dms: number,
+    ads: number,
+    cdc: number,
   }
 }
 